@@ -1,5 +1,6 @@
 #![allow(warnings)]
 
+use crate::lex::Span;
 use std::fmt;
 
 impl fmt::Display for Letter {
@@ -20,8 +21,91 @@ impl fmt::Display for Letter {
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for Accidental {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Accidental::Sharp => "#",
+                Accidental::Flat => "b",
+                Accidental::DoubleSharp => "x",
+                Accidental::DoubleFlat => "bb",
+            }
+        )
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letter)?;
+        if let Some(acc) = &self.acc {
+            write!(f, "{}", acc)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Song {
+    pub metadata: SongMetadata,
+    pub sections: Vec<Section>,
+}
+
+/// The optional `name: value` header at the top of a chart:
+///
+/// ```text
+/// title: Don't Stop Believin'
+/// artist: Journey
+/// key: E
+/// tempo: 119
+/// meter: 4/4
+/// style: Rock
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SongMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub key: Option<Key>,
+    pub tempo: Option<u16>, // beats per minute
+    /// The meter of the bars before the first one that states its own.
+    pub meter: Option<Meter>,
+    pub style: Option<String>,
+    /// Comments written above the header, kept there by `fmt`.
+    pub comments: Vec<String>,
+}
+
+/// A key such as `F#m`: a tonic note and whether the key is minor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub tonic: Note,
+    pub minor: bool,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.tonic, if self.minor { "m" } else { "" })
+    }
+}
+
+impl Song {
+    /// Every bar of the song in order, across sections.
+    pub fn bars(&self) -> impl Iterator<Item = &Bar> {
+        self.sections.iter().flat_map(|s| s.bars.iter())
+    }
+
+    pub fn bars_mut(&mut self) -> impl Iterator<Item = &mut Bar> {
+        self.sections.iter_mut().flat_map(|s| s.bars.iter_mut())
+    }
+}
+
+/// The bars from one label (`[Verse]`, `*A`) to the next. Bars before the first label,
+/// or in a chart without labels, form a section with no label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub label: Option<String>,
+    /// Comments on lines of their own just above the label.
+    pub comments: Vec<String>,
     pub bars: Vec<Bar>,
 }
 
@@ -29,17 +113,77 @@ pub struct Song {
 pub struct Bar {
     /// Optional per-bar meter, e.g., 4/4. Keep here if meter can change per bar.
     pub meter: Option<Meter>,
-    /// Sequence of bar items (NC, %, or a single chord).
-    pub items: Vec<BarItem>,
+    /// Sequence of bar items (NC, %, or a single chord), in the order they are played.
+    pub slots: Vec<Slot>,
+    /// From the meter (or first item) to the last item, bar lines excluded.
+    pub span: Span,
+    /// The bar opens a repeat: `|: C | ...`.
+    pub repeat_start: bool,
+    /// The bar line that closes the bar. The last bar of a song always ends in
+    /// `||` or `:|`.
+    pub end: BarLine,
+    /// First, second... ending: `1. C | D :| 2. E ||`, empty for a bar that does not
+    /// start one. An ending may be shared by several passes (`1. 2. D :| 3. F ||`). It
+    /// runs up to its `:|` or `||`, or up to the next numbered ending.
+    pub volta: Vec<u8>,
+    /// Segno and Coda, written before the bar's chords; To Coda, Fine, D.C. and
+    /// D.S., written after them.
+    pub marks: Vec<NavMark>,
+    /// Comments on lines of their own just above the bar, as written (`# solo`).
+    pub comments_before: Vec<String>,
+    /// Comments after the bar's chords, up to the next bar (`C | // check this`).
+    pub comments_after: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarLine {
+    #[default]
+    Single, // "|"
+    Double,    // "||"
+    RepeatEnd, // ":|"
+}
+
+/// Road-map markings; see [`crate::expand::unroll`] for how they are played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavMark {
+    Segno,
+    Coda,
+    ToCoda,
+    Fine,
+    DaCapo(Option<Until>),   // D.C. [al Coda | al Fine]
+    DalSegno(Option<Until>), // D.S. [al Coda | al Fine]
+}
+
+/// Where a D.C. or D.S. goes on to after the jump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    Coda, // take the "To Coda" jump
+    Fine, // stop at "Fine"
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Meter {
     pub numerator: u16,   // 1..=15
     pub denominator: u16, // {1,2,4,8,16}
 }
 
-#[derive(Debug, Clone)]
+/// A bar item and where it falls in the bar. Beats are counted in the bar's meter
+/// (eighth notes in 6/8); `start` and `duration` are filled in by
+/// [`crate::meter::assign_timing`] once the whole song is parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    pub item: BarItem,
+    /// Length written in the chart: `C:3`, or `C..` (one beat plus one per dot).
+    /// Items without one share whatever the bar has left equally.
+    pub beats: Option<u16>,
+    pub start: f32, // beats from the start of the bar
+    pub duration: f32,
+    /// The words sung from this item up to the next, in charts read from ChordPro
+    /// (`[Am]Hello ` gives `Hello `).
+    pub lyric: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BarItem {
     NC,           // "NC"
     Repeat,       // "%"
@@ -49,8 +193,9 @@ pub enum BarItem {
 #[derive(Debug, Clone)]
 pub struct Chord {
     pub root: Note,                       // required
-    pub description: Option<Description>, // [qual][qnum][add][sus][omit]
+    pub description: Option<Description>, // [qual][qnum]{add}[sus][omit]
     pub bass: Option<Note>,               // optional "/ <note>"
+    pub span: Span,                       // root through bass
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -68,20 +213,56 @@ pub enum Letter {
 pub enum Accidental {
     Sharp,
     Flat,
-} // '#', 'b'
+    DoubleSharp,
+    DoubleFlat,
+} // '#', 'b', 'x', 'bb'
 
 #[derive(Debug, Clone)]
 pub struct Note {
     pub letter: Letter,          // A..G
-    pub acc: Option<Accidental>, // # or b
+    pub acc: Option<Accidental>, // #, b, x or bb
+    pub span: Span,              // empty (default) for notes made up by calc or transpose
 }
 
-/// Description bundles optionals. Enforce "qual & sus cannot coexist" in the parser.
-#[derive(Debug, Clone)]
+// Spans say where a node came from, not what it is: "C7" typed at the start of a chart
+// and "C7" typed at the end are the same chord, so equality ignores them.
+
+impl PartialEq for Bar {
+    fn eq(&self, other: &Self) -> bool {
+        self.meter == other.meter
+            && self.slots == other.slots
+            && self.repeat_start == other.repeat_start
+            && self.end == other.end
+            && self.volta == other.volta
+            && self.marks == other.marks
+            && self.comments_before == other.comments_before
+            && self.comments_after == other.comments_after
+    }
+}
+
+impl PartialEq for Chord {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.description == other.description && self.bass == other.bass
+    }
+}
+
+impl PartialEq for Note {
+    fn eq(&self, other: &Self) -> bool {
+        self.letter == other.letter && self.acc == other.acc
+    }
+}
+
+/// Description bundles optionals. Where `sus` may appear is enforced in the parser:
+/// - at most one sus per chord;
+/// - right after the quality (`Csus4 7`) or after the number and additions
+///   (`C7sus4`, `C9sus4`, `C13(b9)sus4`); both spell the same chord;
+/// - with `-`, `+`, `o` or `ø` it replaces the third and keeps the rest (`C-7sus2`),
+///   but it is rejected after `5` or `1`, which have no third to suspend.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Description {
-    pub qual: Option<Qual>, // "-", "+", "o", "5", "1"
+    pub qual: Option<Qual>, // "-", "+", "o", "ø", "5", "1"
     pub qnum: Option<Qnum>, // e.g., 6, 7, ^6, ^7, maybe with ext
-    pub add: Option<Add>,   // "(" ... ")" or without parens
+    pub add: Vec<Add>,      // in order: "(b9,#11)", "(b9)(#11)" or bare "b9#11"
     pub sus: Option<Sus>,   // sus2/sus4/sus24
     pub omit: Option<Omit>, // no3/no5/no35
 }
@@ -91,9 +272,10 @@ pub enum Qual {
     Minus,
     Plus,
     LowerO,
+    HalfDim,
     Five,
     One,
-} // -, +, o, 5, 1
+} // -, +, o, ø (also -7b5), 5, 1
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Ext {
@@ -109,7 +291,7 @@ pub struct Qnum {
     pub ext: Option<Ext>, // optional 9/11/13
 }
 
-/// add ::= alt | "(" alt ")"
+/// add ::= alt | "(" alt {"," alt} ")"
 /// alt ::= [acc] "5" | [acc] ext
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Add {
@@ -1,22 +1,109 @@
 use crate::ast::*;
-
-pub fn analyze_song(song: &Song) {
-    for bar in song.bars.iter() {
-        //println!("Bar {}:", i + 1);
-        for item in &bar.items {
-            if let BarItem::Chord(ch) = item {
-                let _pcs = chord_to_pitch_classes(ch);
-                //println!("  {:?} -> {:?}", ch, pcs);
+use crate::diag::Diagnostic;
+use crate::lex::Span;
+
+/// Look for chords that are valid but probably not what was meant, such as `C/C`.
+/// Each warning points at the chord (or bass note) in the source.
+pub fn analyze_song(song: &Song) -> Vec<Diagnostic> {
+    let mut warnings = Vec::new();
+    for bar in song.bars() {
+        for slot in &bar.slots {
+            if let BarItem::Chord(ch) = &slot.item {
+                check_chord(ch, &mut warnings);
             }
         }
     }
+    warnings
+}
+
+fn check_chord(ch: &Chord, warnings: &mut Vec<Diagnostic>) {
+    if let Some(bass) = &ch.bass {
+        if note_to_pc(bass) == note_to_pc(&ch.root) {
+            warnings.push(
+                Diagnostic::warning("bass note duplicates root", bass.span.clone()).with_hint(
+                    format!("`{}/{}` sounds the same as `{}`", ch.root, bass, ch.root),
+                ),
+            );
+        }
+    }
+
+    // "C5no3": the omission removes nothing
+    if let Some(omit) = ch.description.as_ref().and_then(|d| d.omit) {
+        let mut full = ch.clone();
+        if let Some(d) = &mut full.description {
+            d.omit = None;
+        }
+        let tones = chord_tones(&full);
+        let has = |degree: u8| tones.iter().any(|t| t.degree == degree);
+        let missing = match omit {
+            Omit::No3 => !has(3),
+            Omit::No5 => !has(5),
+            Omit::No35 => !has(3) || !has(5),
+        };
+        if missing {
+            warnings.push(Diagnostic::warning(
+                "omitted tone is not in the chord",
+                ch.span.clone(),
+            ));
+        }
+    }
 }
 
 // ---------------------------------------------------------
 // Main chord evaluation
 // ---------------------------------------------------------
 
+/// A chord tone: the interval degree it was built as (1, 3, 5, 7, 9, 11, 13, or
+/// 2/4/6 for sus and sixth chords) and its distance from the root in semitones.
+/// Keeping the degree is what lets us spell `Db7` as `Db F Ab Cb` rather than `C# F G# B`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tone {
+    pub degree: u8,
+    pub semis: u8,
+}
+
 pub fn chord_to_pitch_classes(chord: &Chord) -> Vec<u8> {
+    // Convert to pitch classes
+    let root_pc = note_to_pc(&chord.root);
+    let mut pcs = to_pitch_classes(root_pc, &chord_tones(chord));
+
+    // Inversion: add bass if needed
+    if let Some(bass) = &chord.bass {
+        let bass_pc = note_to_pc(bass);
+        if !pcs.contains(&bass_pc) {
+            pcs.insert(0, bass_pc);
+        }
+    }
+
+    pcs
+}
+
+/// The chord's notes spelled from its root, ordered by degree (root, 3rd, 5th, 7th, 9th...).
+/// Each tone gets the letter its degree calls for, so double sharps and double flats
+/// appear where needed (`Co7` is `C Eb Gb Bbb`). A slash bass that is not already a
+/// chord tone comes first, spelled as written.
+pub fn chord_to_spelled_notes(chord: &Chord) -> Vec<Note> {
+    let root_pc = note_to_pc(&chord.root);
+    let mut tones = chord_tones(chord);
+    tones.sort_by_key(|t| t.degree);
+
+    let mut notes: Vec<Note> = tones
+        .iter()
+        .map(|t| spell_tone(&chord.root, root_pc, t))
+        .collect();
+
+    if let Some(bass) = &chord.bass {
+        let bass_pc = note_to_pc(bass);
+        if !tones.iter().any(|t| (root_pc + t.semis) % 12 == bass_pc) {
+            notes.insert(0, bass.clone());
+        }
+    }
+
+    notes
+}
+
+/// The chord's tones relative to its root, before any slash bass is considered.
+pub fn chord_tones(chord: &Chord) -> Vec<Tone> {
     let mut desc = chord.description.clone();
 
     // Handle “5” quality as power chord if no explicit qual was given
@@ -31,43 +118,51 @@ pub fn chord_to_pitch_classes(chord: &Chord) -> Vec<u8> {
     }
 
     // 1. Start with base triad intervals (Table I)
-    let mut intervals = base_intervals(desc.as_ref().and_then(|d| d.qual));
+    let mut tones = base_tones(desc.as_ref().and_then(|d| d.qual));
 
     // 2. Apply extensions (#, 6, 7, 9, 11, 13) (Table III)
     if let Some(d) = &desc {
-        intervals = apply_qnum(intervals, d.qnum.as_ref());
+        tones = apply_qnum(tones, d.qual, d.qnum.as_ref());
 
-        // 3. Additions ((9), (#11), etc.)
-        intervals = apply_add(intervals, d.add.as_ref());
+        // 3. Suspensions (sus2, sus4, sus24), before the additions so that
+        //    a #9 is not mistaken for the third being suspended
+        tones = apply_sus(tones, d.sus.as_ref());
 
-        // 4. Suspensions (sus2, sus4, sus24)
-        intervals = apply_sus(intervals, d.sus.as_ref());
+        // 4. Additions ((9), (#11), etc.)
+        tones = apply_add(tones, &d.add);
 
         // 5. Omissions (no3, no5, no35)
-        intervals = apply_omit(intervals, d.omit.as_ref());
+        tones = apply_omit(tones, d.omit.as_ref());
     }
 
-    // Convert to pitch classes
-    let root_pc = note_to_pc(&chord.root);
-    let mut pcs = to_pitch_classes(root_pc, &intervals);
+    tones
+}
 
-    // Inversion: add bass if needed
-    if let Some(bass) = &chord.bass {
-        let bass_pc = note_to_pc(bass);
-        if !pcs.contains(&bass_pc) {
-            pcs.insert(0, bass_pc);
-        }
+// Add a tone unless that pitch is already in the chord (e.g. the 13th of a o13 is the bb7)
+fn push_tone(tones: &mut Vec<Tone>, degree: u8, semis: u8) {
+    if !tones.iter().any(|t| t.semis == semis) {
+        tones.push(Tone { degree, semis });
     }
-
-    pcs
 }
 
 // ---------------------------------------------------------
 // ROOT → pitch class
 // ---------------------------------------------------------
 
-fn note_to_pc(note: &Note) -> u8 {
-    let base = match note.letter {
+pub(crate) fn note_to_pc(note: &Note) -> u8 {
+    let base = letter_pc(note.letter);
+
+    match note.acc {
+        Some(Accidental::Sharp) => (base + 1) % 12,
+        Some(Accidental::Flat) => (base + 11) % 12,
+        Some(Accidental::DoubleSharp) => (base + 2) % 12,
+        Some(Accidental::DoubleFlat) => (base + 10) % 12,
+        None => base,
+    }
+}
+
+fn letter_pc(letter: Letter) -> u8 {
+    match letter {
         Letter::C => 0,
         Letter::D => 2,
         Letter::E => 4,
@@ -75,12 +170,6 @@ fn note_to_pc(note: &Note) -> u8 {
         Letter::G => 7,
         Letter::A => 9,
         Letter::B => 11,
-    };
-
-    match note.acc {
-        Some(Accidental::Sharp) => (base + 1) % 12,
-        Some(Accidental::Flat) => (base + 11) % 12,
-        None => base,
     }
 }
 
@@ -88,14 +177,16 @@ fn note_to_pc(note: &Note) -> u8 {
 // QUALITIES
 // ---------------------------------------------------------
 
-fn base_intervals(qual: Option<Qual>) -> Vec<u8> {
+fn base_tones(qual: Option<Qual>) -> Vec<Tone> {
+    let tone = |degree, semis| Tone { degree, semis };
     match qual {
-        Some(Qual::Minus) => vec![0, 3, 7],  // minor
-        Some(Qual::Plus) => vec![0, 4, 8],   // augmented
-        Some(Qual::LowerO) => vec![0, 3, 6], // diminished
-        Some(Qual::Five) => vec![0, 7],      // power chord
-        Some(Qual::One) => vec![0],          // unison
-        _ => vec![0, 4, 7],                  // major
+        Some(Qual::Minus) => vec![tone(1, 0), tone(3, 3), tone(5, 7)], // minor
+        Some(Qual::Plus) => vec![tone(1, 0), tone(3, 4), tone(5, 8)],  // augmented
+        Some(Qual::LowerO) => vec![tone(1, 0), tone(3, 3), tone(5, 6)], // diminished
+        Some(Qual::HalfDim) => vec![tone(1, 0), tone(3, 3), tone(5, 6), tone(7, 10)], // half-diminished (m7b5)
+        Some(Qual::Five) => vec![tone(1, 0), tone(5, 7)],                             // power chord
+        Some(Qual::One) => vec![tone(1, 0)],                                          // unison
+        _ => vec![tone(1, 0), tone(3, 4), tone(5, 7)],                                // major
     }
 }
 
@@ -103,140 +194,217 @@ fn base_intervals(qual: Option<Qual>) -> Vec<u8> {
 // EXTENSIONS
 // ---------------------------------------------------------
 
-fn apply_qnum(mut intervals: Vec<u8>, qnum: Option<&Qnum>) -> Vec<u8> {
+fn apply_qnum(mut tones: Vec<Tone>, qual: Option<Qual>, qnum: Option<&Qnum>) -> Vec<Tone> {
     if let Some(qn) = qnum {
-        // n = 6 or 7
-        if let Some(n) = qn.n {
-            match n {
-                6 => intervals.push(9), // sixth
-                7 => {
-                    // Seventh
-                    let seventh = if qn.hat { 11 } else { 10 };
-                    intervals.push(seventh);
-                }
-                9 => {
-                    intervals.push(10); // b7
-                    intervals.push(2); // 9th
-                }
-                13 => {
-                    intervals.push(10); // b7
-                    intervals.push(9); // 13th
-                }
-                _ => {}
+        // The highest degree named, whether written "9" (n) or "^9" (ext)
+        let top = qn.n.or(qn.ext.map(|ext| match ext {
+            Ext::Nine => 9,
+            Ext::Eleven => 11,
+            Ext::Thirteen => 13,
+        }));
+        let seventh = seventh_for(qual, qn.hat);
+
+        match top {
+            Some(6) => push_tone(&mut tones, 6, 9), // sixth
+            Some(7) => set_seventh(&mut tones, seventh),
+            Some(9) => {
+                set_seventh(&mut tones, seventh);
+                push_tone(&mut tones, 9, 2); // 9th
             }
-        }
-
-        // Extensions (9, 11, 13)
-        if let Some(ext) = &qn.ext {
-            let seventh = if qn.hat { 11 } else { 10 };
-            intervals.push(seventh);
-
-            match ext {
-                Ext::Nine => intervals.push(2),
-                Ext::Eleven => intervals.push(5),
-                Ext::Thirteen => intervals.push(9),
+            Some(11) => {
+                set_seventh(&mut tones, seventh);
+                push_tone(&mut tones, 9, 2); // 9th
+                push_tone(&mut tones, 11, 5); // 11th
+            }
+            Some(13) => {
+                // the 11th is left out, it clashes with the 3rd
+                set_seventh(&mut tones, seventh);
+                push_tone(&mut tones, 9, 2); // 9th
+                push_tone(&mut tones, 13, 9); // 13th
             }
+            _ => {}
         }
 
         // Remove 7th when parentheses-numbered extension
         // is present — handled in apply_add
     }
 
-    intervals.sort();
-    intervals.dedup();
-    intervals
+    tones
+}
+
+// Which seventh a quality takes: "^" always means the major 7th, otherwise
+// diminished chords get the bb7 and everything else the minor 7th
+fn seventh_for(qual: Option<Qual>, hat: bool) -> u8 {
+    match (qual, hat) {
+        (_, true) => 11,
+        (Some(Qual::LowerO), false) => 9,
+        _ => 10,
+    }
+}
+
+// A chord has a single seventh; replace the one implied by the quality (e.g. ø) if any
+fn set_seventh(tones: &mut Vec<Tone>, seventh: u8) {
+    tones.retain(|t| t.degree != 7);
+    push_tone(tones, 7, seventh);
 }
 
 // ---------------------------------------------------------
 // ADDITIONS
 // ---------------------------------------------------------
 
-fn apply_add(mut intervals: Vec<u8>, add: Option<&Add>) -> Vec<u8> {
-    if let Some(a) = add {
-        match a {
-            Add::Acc5(acc) => {
-                let mut fifth = 7;
-                if let Some(Accidental::Sharp) = acc {
-                    fifth = 8;
-                }
-                if let Some(Accidental::Flat) = acc {
-                    fifth = 6;
-                }
-                intervals.push(fifth);
-            }
-
-            Add::AccExt(acc, ext) => {
-                let (mut iv, _include_seventh) = match ext {
-                    Ext::Nine => (2, false),
-                    Ext::Eleven => (5, false),
-                    Ext::Thirteen => (9, false),
-                };
-
-                // apply accidental
-                if let Some(Accidental::Sharp) = acc {
-                    iv = (iv + 1) % 12;
-                }
-                if let Some(Accidental::Flat) = acc {
-                    iv = (iv + 11) % 12;
-                }
-
-                intervals.push(iv);
+fn apply_add(mut tones: Vec<Tone>, adds: &[Add]) -> Vec<Tone> {
+    let mut written: Vec<Tone> = Vec::new();
+    for a in adds {
+        let (degree, natural) = match a {
+            Add::Acc5(_) => (5, 7),
+            Add::AccExt(_, Ext::Nine) => (9, 2),
+            Add::AccExt(_, Ext::Eleven) => (11, 5),
+            Add::AccExt(_, Ext::Thirteen) => (13, 9),
+        };
+        let acc = match a {
+            Add::Acc5(acc) | Add::AccExt(acc, _) => *acc,
+        };
+
+        // apply accidental; an altered fifth or tension replaces the natural one
+        let semis = match acc {
+            Some(Accidental::Sharp) => (natural + 1) % 12,
+            Some(Accidental::Flat) => (natural + 11) % 12,
+            Some(Accidental::DoubleSharp) => (natural + 2) % 12,
+            Some(Accidental::DoubleFlat) => (natural + 10) % 12,
+            None => natural,
+        };
+        // a written alteration is its own degree even where it sounds like another tone:
+        // the #9 of C-7(#9) is a D#, not a second Eb. It replaces the tone the chord
+        // already had there, but not another alteration written with it (C7(b9,#9))
+        if acc.is_some() {
+            let tone = Tone { degree, semis };
+            tones.retain(|t| t.degree != degree || written.contains(t));
+            if !tones.contains(&tone) {
+                tones.push(tone);
             }
+            written.push(tone);
+        } else {
+            push_tone(&mut tones, degree, semis);
         }
     }
 
-    intervals.sort();
-    intervals.dedup();
-    intervals
+    tones
 }
 
 // ---------------------------------------------------------
 //  SUSPENSIONS
 // ---------------------------------------------------------
 
-fn apply_sus(mut intervals: Vec<u8>, sus: Option<&Sus>) -> Vec<u8> {
+fn apply_sus(mut tones: Vec<Tone>, sus: Option<&Sus>) -> Vec<Tone> {
     if let Some(s) = sus {
         // remove 3rd
-        intervals.retain(|&i| i != 3 && i != 4);
+        tones.retain(|t| t.degree != 3);
 
         match s {
-            Sus::Sus2 => intervals.push(2),
-            Sus::Sus4 => intervals.push(5),
+            Sus::Sus2 => push_tone(&mut tones, 2, 2),
+            Sus::Sus4 => push_tone(&mut tones, 4, 5),
             Sus::Sus24 => {
-                intervals.push(2);
-                intervals.push(5);
+                push_tone(&mut tones, 2, 2);
+                push_tone(&mut tones, 4, 5);
             }
         }
     }
 
-    intervals.sort();
-    intervals.dedup();
-    intervals
+    tones
 }
 
 // ---------------------------------------------------------
 // OMISSIONS
 // ---------------------------------------------------------
 
-fn apply_omit(mut intervals: Vec<u8>, omit: Option<&Omit>) -> Vec<u8> {
+fn apply_omit(mut tones: Vec<Tone>, omit: Option<&Omit>) -> Vec<Tone> {
     if let Some(o) = omit {
         match o {
-            Omit::No3 => intervals.retain(|&i| i != 3 && i != 4),
-            Omit::No5 => intervals.retain(|&i| i != 7),
-            Omit::No35 => intervals.retain(|&i| i != 3 && i != 4 && i != 7),
+            Omit::No3 => tones.retain(|t| t.degree != 3),
+            Omit::No5 => tones.retain(|t| t.degree != 5),
+            Omit::No35 => tones.retain(|t| t.degree != 3 && t.degree != 5),
         }
     }
-    intervals
+    tones
 }
 
 // ---------------------------------------------------------
 // CONVERSION TO PITCH CLASSES
 // ---------------------------------------------------------
 
-fn to_pitch_classes(root_pc: u8, intervals: &[u8]) -> Vec<u8> {
-    let mut pcs: Vec<u8> = intervals.iter().map(|i| (root_pc + i) % 12).collect();
+fn to_pitch_classes(root_pc: u8, tones: &[Tone]) -> Vec<u8> {
+    let mut pcs: Vec<u8> = tones.iter().map(|t| (root_pc + t.semis) % 12).collect();
 
     pcs.sort();
     pcs.dedup();
     pcs
 }
+
+// ---------------------------------------------------------
+// SPELLING
+// ---------------------------------------------------------
+
+const LETTERS: [Letter; 7] = [
+    Letter::C,
+    Letter::D,
+    Letter::E,
+    Letter::F,
+    Letter::G,
+    Letter::A,
+    Letter::B,
+];
+
+// The degree picks the letter (a 3rd above D is always some F), the semitones pick the accidental
+fn spell_tone(root: &Note, root_pc: u8, tone: &Tone) -> Note {
+    let root_idx = LETTERS.iter().position(|l| *l == root.letter).unwrap_or(0);
+    let letter = LETTERS[(root_idx + tone.degree as usize - 1) % 7];
+    let target = (root_pc + tone.semis) % 12;
+
+    // signed distance from the natural letter to the target pitch, in -6..=5
+    let diff = (target as i8 - letter_pc(letter) as i8 + 18) % 12 - 6;
+    let acc = match diff {
+        0 => None,
+        1 => Some(Accidental::Sharp),
+        2 => Some(Accidental::DoubleSharp),
+        -1 => Some(Accidental::Flat),
+        -2 => Some(Accidental::DoubleFlat),
+        // beyond a double accidental (e.g. #9 over B#): fall back to the plainest spelling
+        _ => {
+            let flats = matches!(
+                root.acc,
+                Some(Accidental::Flat) | Some(Accidental::DoubleFlat)
+            );
+            return plain_spelling(target, flats);
+        }
+    };
+    Note {
+        letter,
+        acc,
+        span: Span::default(),
+    }
+}
+
+/// Spell a pitch class with at most one accidental, using flats or sharps for the black keys.
+pub(crate) fn plain_spelling(pc: u8, flats: bool) -> Note {
+    let (letter, acc) = match (pc, flats) {
+        (1, true) => (Letter::D, Some(Accidental::Flat)),
+        (3, true) => (Letter::E, Some(Accidental::Flat)),
+        (6, true) => (Letter::G, Some(Accidental::Flat)),
+        (8, true) => (Letter::A, Some(Accidental::Flat)),
+        (10, true) => (Letter::B, Some(Accidental::Flat)),
+        (1, false) => (Letter::C, Some(Accidental::Sharp)),
+        (3, false) => (Letter::D, Some(Accidental::Sharp)),
+        (6, false) => (Letter::F, Some(Accidental::Sharp)),
+        (8, false) => (Letter::G, Some(Accidental::Sharp)),
+        (10, false) => (Letter::A, Some(Accidental::Sharp)),
+        _ => (
+            *LETTERS.iter().find(|l| letter_pc(**l) == pc).unwrap(),
+            None,
+        ),
+    };
+    Note {
+        letter,
+        acc,
+        span: Span::default(),
+    }
+}
@@ -0,0 +1,312 @@
+use crate::ast::{Bar, BarItem, BarLine, Section, Slot, Song};
+use crate::diag::Diagnostic;
+use crate::lex::Span;
+use crate::meter::assign_timing;
+use crate::parse::{parse_chord_in, set_header_field, ParseOptions};
+use crate::table::{chord_to_string, SymbolStyle};
+
+/// Whether a file is ChordPro rather than a bar chart, going by its extension.
+pub fn is_chordpro_path(path: &str) -> bool {
+    [".cho", ".crd", ".chopro", ".chordpro"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// Read a ChordPro file: lyric lines with chords inline (`[Am]Hello [F]darkness`) and
+/// `{directive: value}` lines. Every chord becomes a bar of its own, holding the words
+/// sung from it up to the next chord; words before the first chord are sung over NC.
+/// `{title}`, `{artist}`, `{key}`, `{tempo}`, `{time}` and `{style}` fill in the metadata,
+/// `{start_of_chorus}` (and verse, bridge) opens a labelled section, and `{comment}` and
+/// `#` lines become comments above the next bar.
+/// Bad chords and metadata are errors; directives that are not understood are warnings.
+pub fn parse_chordpro(src: &str, opts: &ParseOptions) -> (Song, Vec<Diagnostic>) {
+    let mut reader = Reader {
+        src,
+        opts,
+        song: Song {
+            metadata: Default::default(),
+            sections: Vec::new(),
+        },
+        diags: Vec::new(),
+        comments: Vec::new(),
+        section_closed: false,
+    };
+    let mut lo = 0;
+    for line in src.split_inclusive('\n') {
+        reader.line(lo, line.trim_end());
+        lo += line.len();
+    }
+
+    let mut song = reader.song;
+    if let Some(last) = song.bars_mut().last() {
+        last.comments_after.append(&mut reader.comments);
+        last.end = BarLine::Double;
+    }
+    assign_timing(&mut song);
+    (song, reader.diags)
+}
+
+/// Write a song back out as ChordPro, chords inline with their lyrics, so a lyric sheet
+/// keeps its words through `fmt` and `transpose`. Comments are written as `#` lines.
+pub fn song_to_chordpro(song: &Song, style: SymbolStyle) -> String {
+    let mut out = String::new();
+    let meta = &song.metadata;
+    own_lines(&mut out, &meta.comments);
+    let fields = [
+        ("title", meta.title.clone()),
+        ("artist", meta.artist.clone()),
+        ("key", meta.key.as_ref().map(|k| k.to_string())),
+        ("tempo", meta.tempo.map(|t| t.to_string())),
+        (
+            "time",
+            meta.meter
+                .as_ref()
+                .map(|m| format!("{}/{}", m.numerator, m.denominator)),
+        ),
+        ("style", meta.style.clone()),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            out.push_str(&format!("{{{}: {}}}\n", name, value));
+        }
+    }
+
+    let mut first = true;
+    for section in &song.sections {
+        let kind = section.label.as_deref().map(section_kind);
+        if let (Some(label), Some(kind)) = (&section.label, kind) {
+            own_lines(&mut out, &section.comments);
+            if label.eq_ignore_ascii_case(kind) {
+                out.push_str(&format!("{{start_of_{}}}\n", kind));
+            } else {
+                out.push_str(&format!("{{start_of_{}: {}}}\n", kind, label));
+            }
+        }
+        for bar in &section.bars {
+            own_lines(&mut out, &bar.comments_before);
+            for slot in &bar.slots {
+                match &slot.item {
+                    BarItem::Chord(ch) => {
+                        out.push_str(&format!("[{}]", chord_to_string(ch, style)))
+                    }
+                    // words before the first chord are read back as sung over NC
+                    BarItem::NC if first => {}
+                    BarItem::NC => out.push_str("[NC]"),
+                    BarItem::Repeat => {}
+                }
+                first = false;
+                out.push_str(slot.lyric.as_deref().unwrap_or(""));
+            }
+            own_lines(&mut out, &bar.comments_after);
+        }
+        if let Some(kind) = kind {
+            end_line(&mut out);
+            out.push_str(&format!("{{end_of_{}}}\n", kind));
+        }
+    }
+    out.trim_end().to_string()
+}
+
+// ChordPro sections are choruses, verses or bridges; other labels are written as verses
+fn section_kind(label: &str) -> &'static str {
+    let label = label.to_ascii_lowercase();
+    if label.starts_with("chorus") {
+        "chorus"
+    } else if label.starts_with("bridge") {
+        "bridge"
+    } else {
+        "verse"
+    }
+}
+
+fn own_lines(out: &mut String, lines: &[String]) {
+    for line in lines {
+        end_line(out);
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+fn end_line(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    opts: &'a ParseOptions,
+    song: Song,
+    diags: Vec<Diagnostic>,
+    comments: Vec<String>, //waiting for the next bar or section
+    section_closed: bool,  //after {end_of_chorus}, bars go in a section with no label
+}
+
+impl Reader<'_> {
+    // One line, without its line break, starting at byte `lo` of the source
+    fn line(&mut self, lo: usize, text: &str) {
+        let trimmed = text.trim_start();
+        let lo = lo + (text.len() - trimmed.len());
+        let span = Span {
+            lo,
+            hi: lo + trimmed.len(),
+        };
+        if trimmed.is_empty() {
+            return;
+        }
+        if trimmed.starts_with('#') {
+            self.comments.push(trimmed.to_string());
+        } else if let Some(inner) = trimmed.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+            let inner_lo = lo + 1 + (inner.len() - inner.trim_start().len());
+            self.directive(inner.trim(), inner_lo, span);
+        } else {
+            self.lyric_line(lo, trimmed);
+        }
+    }
+
+    // `inner` is the directive without its braces, starting at `inner_lo` in the source
+    fn directive(&mut self, inner: &str, inner_lo: usize, span: Span) {
+        let (name, value) = match inner.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => (inner, ""),
+        };
+        match name {
+            "title" | "t" | "artist" | "key" | "tempo" | "time" | "style" => {
+                let field = match name {
+                    "t" => "title",
+                    "time" => "meter",
+                    _ => name,
+                };
+                // the value ends the directive
+                let value_lo = inner_lo + inner.len() - value.len();
+                let meta = &mut self.song.metadata;
+                if let Err(kind) = set_header_field(meta, field, value, value_lo) {
+                    self.diags.push(Diagnostic {
+                        code: Some(kind.code()),
+                        ..Diagnostic::error(kind.to_string(), span)
+                    });
+                }
+            }
+            "comment" | "c" | "comment_italic" | "ci" | "comment_box" | "cb" | "highlight" => {
+                self.comments.push(format!("# {}", value))
+            }
+            "start_of_chorus" | "soc" => self.open_section(value, "Chorus"),
+            "start_of_verse" | "sov" => self.open_section(value, "Verse"),
+            "start_of_bridge" | "sob" => self.open_section(value, "Bridge"),
+            "end_of_chorus" | "eoc" | "end_of_verse" | "eov" | "end_of_bridge" | "eob" => {
+                self.section_closed = true
+            }
+            _ => self.diags.push(Diagnostic::warning(
+                format!("directive `{}` is not supported and is ignored", name),
+                span,
+            )),
+        }
+    }
+
+    // {start_of_verse: Verse 2} is labelled "Verse 2", a bare {start_of_verse} "Verse"
+    fn open_section(&mut self, label: &str, default: &str) {
+        let label = if label.is_empty() { default } else { label };
+        self.song.sections.push(Section {
+            label: Some(label.to_string()),
+            comments: std::mem::take(&mut self.comments),
+            bars: Vec::new(),
+        });
+        self.section_closed = false;
+    }
+
+    // "Hello [Am]dark[F]ness", starting at byte `lo` of the source
+    fn lyric_line(&mut self, lo: usize, text: &str) {
+        // the chord sung at the end of the last line goes on over the line break
+        if let Some(slot) = self.last_slot() {
+            slot.lyric.get_or_insert_with(String::new).push('\n');
+        }
+        let first = text.find('[').unwrap_or(text.len());
+        if first > 0 {
+            self.words(&text[..first], lo, lo + first);
+        }
+        let mut i = first;
+        while i < text.len() {
+            let Some(close) = text[i..].find(']') else {
+                let span = Span {
+                    lo: lo + i,
+                    hi: lo + text.len(),
+                };
+                self.diags
+                    .push(Diagnostic::error("`[` without a closing `]`", span));
+                return;
+            };
+            let chord = Span {
+                lo: lo + i + 1,
+                hi: lo + i + close,
+            };
+            let after = i + close + 1;
+            let next = text[after..].find('[').map_or(text.len(), |n| after + n);
+            let words = &text[after..next];
+            let lyric = (!words.is_empty()).then(|| words.to_string());
+            i = next;
+
+            let item = match self.src[chord.lo..chord.hi].trim() {
+                "NC" | "N.C." => BarItem::NC,
+                _ => match parse_chord_in(self.src, &chord, self.opts) {
+                    Ok(ch) => BarItem::Chord(ch),
+                    Err(err) => {
+                        self.diags
+                            .push(Diagnostic::from_parse_error(&err, self.src));
+                        // the words are still sung, over whatever came before
+                        self.words(words, lo + after, lo + next);
+                        continue;
+                    }
+                },
+            };
+            self.bar(item, chord, lyric);
+        }
+    }
+
+    // Words with no chord of their own go on with the last chord, or over NC at the start
+    fn words(&mut self, words: &str, lo: usize, hi: usize) {
+        if words.is_empty() {
+            return;
+        }
+        match self.last_slot() {
+            Some(slot) => slot.lyric.get_or_insert_with(String::new).push_str(words),
+            None => self.bar(BarItem::NC, Span { lo, hi }, Some(words.to_string())),
+        }
+    }
+
+    fn last_slot(&mut self) -> Option<&mut Slot> {
+        self.song
+            .bars_mut()
+            .last()
+            .and_then(|bar| bar.slots.last_mut())
+    }
+
+    fn bar(&mut self, item: BarItem, span: Span, lyric: Option<String>) {
+        if self.song.sections.is_empty() || self.section_closed {
+            self.song.sections.push(Section {
+                label: None,
+                comments: Vec::new(),
+                bars: Vec::new(),
+            });
+            self.section_closed = false;
+        }
+        let slot = Slot {
+            item,
+            beats: None,
+            start: 0.0,
+            duration: 0.0,
+            lyric,
+        };
+        let section = self.song.sections.last_mut().unwrap();
+        section.bars.push(Bar {
+            meter: None,
+            slots: vec![slot],
+            span,
+            repeat_start: false,
+            end: BarLine::Single,
+            volta: Vec::new(),
+            marks: Vec::new(),
+            comments_before: std::mem::take(&mut self.comments),
+            comments_after: Vec::new(),
+        });
+    }
+}
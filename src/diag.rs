@@ -0,0 +1,168 @@
+use crate::lex::{Span, TokKind};
+use crate::parse::ParseError;
+
+// Keywords the lexer recognises as a whole; used to suggest fixes for typos
+const KEYWORDS: [&str; 11] = [
+    "sus4", "sus2", "sus24", "no3", "no5", "no35", "NC", "maj", "min", "dim", "aug",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The chart does not parse.
+    Error,
+    /// The chart parses, but something in it is probably not what was meant.
+    Warning,
+}
+
+/// A message tied to a region of the source, ready to be rendered for the user.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub msg: String,
+    /// Stable error code (e.g. "E002"), see [`crate::parse::ErrorKind::code`].
+    pub code: Option<&'static str>,
+    pub span: Span,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    /// Build a diagnostic from a parse error. When the error sits on a token the lexer
+    /// could not recognise, a hint is attached (e.g. "did you mean `sus4`?").
+    pub fn from_parse_error(err: &ParseError, src: &str) -> Diagnostic {
+        let hint = match err.found {
+            TokKind::Unknown(c) => Some(unknown_hint(src, &err.span, c)),
+            _ => None,
+        };
+        Diagnostic {
+            severity: Severity::Error,
+            msg: err.to_string(),
+            code: Some(err.code()),
+            span: err.span.clone(),
+            hint,
+        }
+    }
+
+    /// An error found after parsing, e.g. by [`crate::expand::expand_repeats`].
+    pub fn error(msg: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            msg: msg.into(),
+            code: None,
+            span,
+            hint: None,
+        }
+    }
+
+    /// A warning about a chart that parsed, e.g. from [`crate::calc::analyze_song`].
+    pub fn warning(msg: impl Into<String>, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            msg: msg.into(),
+            code: None,
+            span,
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Diagnostic {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Map a byte offset to a 1-based (line, column) pair; columns count characters, not bytes.
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let offset = clamp_to_boundary(src, offset);
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let col = src[line_start..offset].chars().count() + 1;
+    (line, col)
+}
+
+/// Render a diagnostic with its location, the offending source line and a `^^^` underline.
+pub fn render(src: &str, diag: &Diagnostic) -> String {
+    let lo = clamp_to_boundary(src, diag.span.lo);
+    let hi = clamp_to_boundary(src, diag.span.hi.max(lo));
+    let (line, col) = line_col(src, lo);
+
+    let line_start = src[..lo].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = src[lo..].find('\n').map(|i| lo + i).unwrap_or(src.len());
+    let text = src[line_start..line_end].trim_end_matches('\r');
+
+    // underline at least one column, never past the end of the line
+    let width = src[lo..hi.min(line_end)].chars().count().max(1);
+    let gutter = " ".repeat(line.to_string().len());
+
+    let label = match diag.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let mut out = match diag.code {
+        Some(code) => format!("{}[{}]: {}\n", label, code, diag.msg),
+        None => format!("{}: {}\n", label, diag.msg),
+    };
+    out.push_str(&format!("{}--> line {}, column {}\n", gutter, line, col));
+    out.push_str(&format!("{} |\n", gutter));
+    out.push_str(&format!("{} | {}\n", line, text));
+    out.push_str(&format!(
+        "{} | {}{}\n",
+        gutter,
+        " ".repeat(col - 1),
+        "^".repeat(width)
+    ));
+    if let Some(hint) = &diag.hint {
+        out.push_str(&format!("{} = hint: {}\n", gutter, hint));
+    }
+    out
+}
+
+// ---------------------------------------------------------
+// HINTS
+// ---------------------------------------------------------
+
+fn unknown_hint(src: &str, span: &Span, c: char) -> String {
+    // the unknown character plus whatever word it starts, e.g. "su4" or "nC"
+    let lo = clamp_to_boundary(src, span.lo);
+    let word: String = src[lo..]
+        .chars()
+        .take_while(|ch| ch.is_ascii_alphanumeric())
+        .collect();
+
+    if word.len() >= 2 {
+        let best = KEYWORDS
+            .iter()
+            .map(|k| (edit_distance(&word, k), *k))
+            .min_by_key(|(d, _)| *d);
+        if let Some((d, k)) = best {
+            if d <= 2 {
+                return format!("did you mean `{}`?", k);
+            }
+        }
+    }
+    format!("`{}` is not part of the chord grammar", c)
+}
+
+// Levenshtein distance over characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+// Spans are byte offsets; make sure we never slice inside a multi-byte character
+fn clamp_to_boundary(src: &str, offset: usize) -> usize {
+    let mut offset = offset.min(src.len());
+    while !src.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
@@ -0,0 +1,242 @@
+use crate::ast::{Bar, BarItem, BarLine, Meter, NavMark, Section, Slot, Song, Until};
+use crate::diag::Diagnostic;
+use crate::meter::{assign_timing, effective_meters, start_meter};
+
+/// Replace every `%` with the chords of the bar before it, as they are played.
+/// Repeats of repeats resolve to the last written bar, a `%` opening a section repeats
+/// the last bar of the section before, and a repeated bar keeps its own
+/// meter, if it states one. The copied chords keep the spans of the chords they copy.
+/// Fails if the first bar is a `%`, as there is nothing to repeat.
+pub fn expand_repeats(song: &Song) -> Result<Song, Diagnostic> {
+    let mut out = song.clone();
+    let mut previous: Option<Vec<Slot>> = None;
+    for bar in out.bars_mut() {
+        if bar.slots.iter().any(|s| s.item == BarItem::Repeat) {
+            let Some(repeated) = &previous else {
+                return Err(Diagnostic::error(
+                    "`%` in the first bar has no bar to repeat",
+                    bar.span.clone(),
+                ));
+            };
+            bar.slots = bar
+                .slots
+                .iter()
+                .flat_map(|slot| match slot.item {
+                    BarItem::Repeat => repeated.clone(),
+                    _ => vec![slot.clone()],
+                })
+                .collect();
+        }
+        previous = Some(bar.slots.clone());
+    }
+    // a repeat in a new meter is played in that meter
+    assign_timing(&mut out);
+    Ok(out)
+}
+
+/// The song as it is played: `|: :|` repeats taken, the ending that matches each pass
+/// chosen, and D.C. / D.S. followed back to the top or the Segno, then on to Fine or,
+/// from "To Coda", to the Coda. Repeats are not taken again after a D.C. or D.S.;
+/// only the last ending is played. The result is plain bars, grouped under the labels
+/// of the sections they come from, with a meter wherever the played meter changes.
+pub fn unroll(song: &Song) -> Result<Song, Diagnostic> {
+    let bars: Vec<(usize, &Bar)> = song
+        .sections
+        .iter()
+        .enumerate()
+        .flat_map(|(i, s)| s.bars.iter().map(move |b| (i, b)))
+        .collect();
+    let n = bars.len();
+    let endings = endings(&bars);
+    let marked = |mark: NavMark, from: usize| (from..n).find(|&i| bars[i].1.marks.contains(&mark));
+    let missing = |msg: &str, bar: &Bar| Diagnostic::error(msg, bar.span.clone());
+
+    let mut order = Vec::new();
+    let mut jumped_back = vec![false; n]; // `:|` bars already repeated from
+    let mut repeat_from = 0;
+    let mut pass = 1;
+    let mut after_jump: Option<Option<Until>> = None; // set by D.C. / D.S.
+    let mut i = 0;
+    while i < n {
+        let bar = bars[i].1;
+        if order.len() > 16 * n {
+            return Err(Diagnostic::error(
+                "the repeats and jumps never reach the end of the chart",
+                bar.span.clone(),
+            ));
+        }
+        if bar.repeat_start && i != repeat_from {
+            repeat_from = i;
+            pass = 1;
+        }
+        if let Some(e) = &endings[i] {
+            let wanted = if after_jump.is_some() {
+                e.last_number
+            } else {
+                pass
+            };
+            if !e.numbers.contains(&wanted) {
+                i += 1;
+                continue;
+            }
+        }
+        order.push(i);
+
+        let has = |mark: NavMark| bar.marks.contains(&mark);
+        if has(NavMark::Fine) && after_jump.is_some() && after_jump != Some(Some(Until::Coda)) {
+            break;
+        }
+        if has(NavMark::ToCoda) && after_jump == Some(Some(Until::Coda)) {
+            i = marked(NavMark::Coda, i + 1)
+                .ok_or_else(|| missing("To Coda, but there is no Coda after this bar", bar))?;
+            continue;
+        }
+        if bar.end == BarLine::RepeatEnd && after_jump.is_none() {
+            // each ending with a `:|` is played once, on its own pass
+            if endings[i].is_some() || !jumped_back[i] {
+                jumped_back[i] = true;
+                i = repeat_from;
+                pass += 1;
+                continue;
+            }
+        }
+        if bar.end == BarLine::RepeatEnd || endings[i].as_ref().is_some_and(|e| e.closes) {
+            repeat_from = i + 1;
+            pass = 1;
+        }
+        if after_jump.is_none() {
+            let jump = bar.marks.iter().find_map(|m| match m {
+                NavMark::DaCapo(until) => Some((0, *until)),
+                NavMark::DalSegno(until) => Some((usize::MAX, *until)),
+                _ => None,
+            });
+            if let Some((target, until)) = jump {
+                if until == Some(Until::Coda) && marked(NavMark::Coda, 0).is_none() {
+                    return Err(missing("al Coda, but the chart has no Coda", bar));
+                }
+                i = if target == 0 {
+                    0
+                } else {
+                    marked(NavMark::Segno, 0)
+                        .ok_or_else(|| missing("D.S., but the chart has no Segno", bar))?
+                };
+                after_jump = Some(until);
+                repeat_from = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+
+    let meters = effective_meters(song);
+    let mut out = Song {
+        metadata: song.metadata.clone(),
+        sections: Vec::new(),
+    };
+    let mut last_section = None;
+    let mut last_meter: Option<Meter> = None;
+    let mut comments = Vec::new(); // of dropped bars, for the next bar played
+    for (k, &i) in order.iter().enumerate() {
+        let (section, bar) = bars[i];
+        // a bar that only held marks ("D.S. al Coda |") has nothing left to play
+        if bar.slots.is_empty() {
+            if !order[..k].contains(&i) {
+                comments.extend(bar.comments_before.iter().cloned());
+                comments.extend(bar.comments_after.iter().cloned());
+            }
+            continue;
+        }
+        if last_section != Some(section) {
+            let first_time = !order[..k].iter().any(|&j| bars[j].0 == section);
+            out.sections.push(Section {
+                label: song.sections[section].label.clone(),
+                comments: match first_time {
+                    true => song.sections[section].comments.clone(),
+                    false => Vec::new(),
+                },
+                bars: Vec::new(),
+            });
+        }
+        // state the meter wherever the played meter changes (the header's or 4/4 start
+        // can stay implicit)
+        let meter = meters[i].clone();
+        let changed = match &last_meter {
+            Some(m) => *m != meter,
+            None => bar.meter.is_some() || meter != start_meter(song),
+        };
+        out.sections.last_mut().unwrap().bars.push(Bar {
+            meter: changed.then(|| meter.clone()),
+            repeat_start: false,
+            end: BarLine::Single,
+            volta: Vec::new(),
+            marks: Vec::new(),
+            ..bar.clone()
+        });
+        // a bar's comments are kept the first time it is played
+        let played = out.bars_mut().last().unwrap();
+        if order[..k].contains(&i) {
+            played.comments_before.clear();
+            played.comments_after.clear();
+        }
+        played.comments_before.splice(0..0, comments.drain(..));
+        last_section = Some(section);
+        last_meter = Some(meter);
+    }
+    if let Some(last) = out.bars_mut().last() {
+        last.end = BarLine::Double;
+        last.comments_after.append(&mut comments);
+    }
+    assign_timing(&mut out);
+    Ok(out)
+}
+
+// A numbered ending a bar belongs to
+struct Ending {
+    numbers: Vec<u8>, // the passes it is played on: "1. 2." -> [1, 2]
+    last_number: u8,  // of the endings in the same group ("1. 2." and "3." -> 3)
+    closes: bool,     // the last bar of the last ending, where the repeat is over
+}
+
+// An ending runs from its "N." to its `:|` or `||`, or up to the next numbered ending
+fn endings(bars: &[(usize, &Bar)]) -> Vec<Option<Ending>> {
+    let mut brackets: Vec<(usize, usize, &[u8])> = Vec::new();
+    let mut i = 0;
+    while i < bars.len() {
+        let numbers = &bars[i].1.volta;
+        if numbers.is_empty() {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while bars[end].1.end == BarLine::Single
+            && end + 1 < bars.len()
+            && bars[end + 1].1.volta.is_empty()
+        {
+            end += 1;
+        }
+        brackets.push((i, end, numbers));
+        i = end + 1;
+    }
+
+    let mut out: Vec<Option<Ending>> = bars.iter().map(|_| None).collect();
+    let mut g = 0;
+    while g < brackets.len() {
+        // endings that follow one another form one group
+        let mut h = g;
+        while h + 1 < brackets.len() && brackets[h + 1].0 == brackets[h].1 + 1 {
+            h += 1;
+        }
+        let last_number = brackets[h].2.iter().copied().max().unwrap_or(0);
+        for &(start, end, numbers) in &brackets[g..=h] {
+            for (i, slot) in out.iter_mut().enumerate().take(end + 1).skip(start) {
+                *slot = Some(Ending {
+                    numbers: numbers.to_vec(),
+                    last_number,
+                    closes: i == end && numbers.contains(&last_number),
+                });
+            }
+        }
+        g = h + 1;
+    }
+    out
+}
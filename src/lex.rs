@@ -1,35 +1,68 @@
 #![allow(warnings)]
 //to track the position of each token in the input
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Span {
     pub lo: usize, //lower bound
     pub hi: usize, //upper bound
 }
 
+impl Span {
+    //smallest span covering both, e.g. from a chord's root to its bass note
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)] //inherit from interfaces custom operators to interact easily with enum and structs
 pub enum TokKind {
     Num(u16),         // e.g. 4, 11, 13 (used for meters or chord numbers)
     NoteLetter(char), // {A, B, C, D, E, F, G}
     Sharp,
-    Flat, //<acc> which can be # or b
+    Flat,        //<acc> which can be # or b (also ♯ / ♭)
+    DoubleSharp, //"x" or "##" (also 𝄪)
+    DoubleFlat,  //"bb" (also 𝄫)
     Dash,
     Plus,
-    LowerO, //<qual> "-", "+", "o"
-    Caret,  //<qnum> "^"
+    LowerO,  //<qual> "-", "+", "o" (also − / °)
+    HalfDim, //"ø", shorthand for -7b5
+    Caret,   //<qnum> "^" (also △ / Δ)
     LParen,
     RParen, //<add> "(", ")"
+    Comma,  //<add> "," between alterations, e.g. (b9,#11)
     Sus2,
     Sus4,
     Sus24, //<sus> "sus2", "sus4", "sus24"
     No3,
     No5,
-    No35,          //<omit> "no3", "no5", "no35"
-    Slash,         //<bass> "/" or for <meter>
-    EOF,           //End of file
-    Bar,           // <bar> "|"
-    NC,            //<chords> NC
-    Percentage,    //<chords> %
-    Unknown(char), //any unrecognized character (for error reporting)
+    No35,                                     //<omit> "no3", "no5", "no35"
+    Slash,                                    //<bass> "/" or for <meter>
+    EOF,                                      //End of file
+    Bar,                                      // <bar> "|"
+    DoubleBar,                                // <bar> "||"
+    RepeatStart,                              // <bar> "|:" or "||:"
+    RepeatEnd,                                // <bar> ":|" or ":||"
+    NC,                                       //<chords> NC
+    Percentage,                               //<chords> %
+    Colon,                                    //<duration> ":" as in C:3
+    Dot,                                      //<duration> "." one more beat, as in C..
+    Label(String), //<section> "[Verse]" or "*A", the name without its markup
+    Header { name: String, value: String }, //<header> "key: F#m" line at the top of the chart
+    Comment { text: String, own_line: bool }, //<trivia> "# ...", "// ..." or "/* ... */", skipped by the parser
+    Segno,                                    //<nav> "Segno" or 𝄋
+    Coda,                                     //<nav> "Coda" or 𝄌
+    ToCoda,                                   //<nav> "To Coda"
+    Fine,                                     //<nav> "Fine"
+    DaCapo,                                   //<nav> "D.C."
+    DalSegno,                                 //<nav> "D.S."
+    Al,                                       //<nav> "al", as in "D.S. al Coda"
+    Maj,                                      //alias "maj" / "M", read like "^"
+    Min,                                      //alias "m" / "min", read like "-"
+    Dim,                                      //alias "dim", read like "o"
+    Aug,                                      //alias "aug", read like "+"
+    Unknown(char),                            //any unrecognized character (for error reporting)
 }
 
 //Each token consists of its kind, text and position span
@@ -40,177 +73,375 @@ pub struct Token {
     pub span: Span,
 }
 
+//The line at lo, if it reads "name: value" with a lowercase name:
+//(name, value, end) with the value trimmed and end at the end of the value
+fn header_line(src: &str, lo: usize) -> Option<(String, String, usize)> {
+    let line = src[lo..].lines().next()?;
+    let (name, value) = line.split_once(':')?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let value = value.trim();
+    let hi = lo + line.trim_end().len();
+    Some((name.to_string(), value.to_string(), hi))
+}
+
+//End of the comment starting at idx, if one does: "//" and "#" run to the end of the line,
+//"/*" to the next "*/" (or the end of the input). "#" starts a comment at the start of a
+//line, or after a space unless it is a sharp (see `hash_is_sharp`), so the sharps in "C#",
+//"C7 #9" and "(b9, #11)" are not comments
+fn comment_end(src: &str, idx: usize) -> Option<usize> {
+    let rest = &src[idx..];
+    let after_space = src[..idx]
+        .chars()
+        .next_back()
+        .is_some_and(char::is_whitespace);
+    let hash = rest
+        .strip_prefix('#')
+        .is_some_and(|after| starts_line(src, idx) || (after_space && !hash_is_sharp(after)));
+    if rest.starts_with("//") || hash {
+        let line = rest.lines().next().unwrap_or("");
+        Some(idx + line.trim_end().len())
+    } else if rest.starts_with("/*") {
+        Some(
+            rest[2..]
+                .find("*/")
+                .map_or(src.len(), |end| idx + 2 + end + 2),
+        )
+    } else {
+        None
+    }
+}
+
+//Whether a "#" after a space, followed by `after`, is a sharp rather than a comment: only an
+//alteration ("#9", "#11") puts a space before its sharp
+pub(crate) fn hash_is_sharp(after: &str) -> bool {
+    after.starts_with(|c: char| c.is_ascii_digit())
+}
+
+//Whether only whitespace comes before idx on its line
+fn starts_line(src: &str, idx: usize) -> bool {
+    src[..idx]
+        .chars()
+        .rev()
+        .take_while(|&c| c != '\n')
+        .all(char::is_whitespace)
+}
+
+//Navigation markings, spelled as on lead sheets
+const NAV_WORDS: [(&str, TokKind); 9] = [
+    ("Segno", TokKind::Segno),
+    ("𝄋", TokKind::Segno),
+    ("Coda", TokKind::Coda),
+    ("𝄌", TokKind::Coda),
+    ("To Coda", TokKind::ToCoda),
+    ("Fine", TokKind::Fine),
+    ("D.C.", TokKind::DaCapo),
+    ("D.S.", TokKind::DalSegno),
+    ("al", TokKind::Al),
+];
+
 //Turn input text into Vec<Token>
 pub fn tokenize(src: &str) -> Vec<Token> {
     //collect tokens into this vector
     let mut toks = vec![];
 
-    //Convert input string to bytes
-    let bytes = src.as_bytes();
-    let mut i = 0usize; //current index
-    let mut pos = 0usize;
+    //byte index of the current character; always kept on a char boundary
+    let mut i = 0usize;
 
-    //helper to push the token into the Vector
-    let mut push = |kind: TokKind, start: usize, end: usize, pos: &mut usize| {
-        let len = end - start;
+    //helper to push the token into the Vector; spans are byte offsets into `src`
+    let mut push = |kind: TokKind, start: usize, end: usize| {
         toks.push(Token {
             kind,
             text: src[start..end].to_string(),
-            span: Span {
-                lo: *pos,
-                hi: *pos + len,
-            },
+            span: Span { lo: start, hi: end },
         });
-        *pos += len;
     };
 
     // helpers to identify the character
-    let peek = |idx: usize| -> Option<u8> {
-        if idx < bytes.len() {
-            Some(bytes[idx])
-        } else {
-            None
-        }
+    let peek = |idx: usize| -> Option<char> { src.get(idx..).and_then(|rest| rest.chars().next()) };
+    let starts_with_at = |idx: usize, s: &str| -> bool { src[idx..].starts_with(s) };
+    //a navigation word at idx; "Fine" and "Coda" only as whole words, not the start of a longer one
+    let word_at = |idx: usize| -> Option<(&'static str, TokKind)> {
+        NAV_WORDS.iter().find_map(|(word, kind)| {
+            let rest = src[idx..].strip_prefix(word)?;
+            let longer = rest.chars().next().is_some_and(|ch| ch.is_alphanumeric());
+            (!longer || !word.ends_with(char::is_alphanumeric)).then(|| (*word, kind.clone()))
+        })
     };
-    let is_space = |b: u8| matches!(b, b' ' | b'\t' | b'\r' | b'\n');
-    let is_digit = |b: u8| (b'0'..=b'9').contains(&b);
-    //let is_note_letter = |b: u8| (b'A'..=b'G').contains(&b);
-    let starts_with_at = |idx: usize, s: &str| -> bool {
-        let end = idx + s.len();
-        end <= bytes.len() && &src[idx..end] == s
+
+    let comment = |lo: usize, hi: usize| TokKind::Comment {
+        text: src[lo..hi].to_string(),
+        own_line: starts_line(src, lo),
     };
 
-    while i < bytes.len() {
-        //skip whitespace
-        while i < bytes.len() && is_space(bytes[i]) {
-            i += 1;
-        }
-        if i >= bytes.len() {
+    //header block: "name: value" lines before the first line of the chart proper,
+    //possibly with comments among them
+    loop {
+        let lo = i + (src[i..].len() - src[i..].trim_start().len());
+        if let Some((name, value, hi)) = header_line(src, lo) {
+            push(TokKind::Header { name, value }, lo, hi);
+            i = hi;
+        } else if let Some(hi) = comment_end(src, lo) {
+            push(comment(lo, hi), lo, hi);
+            i = hi;
+        } else {
             break;
         }
+    }
+
+    while let Some(c) = peek(i) {
+        //skip whitespace (Unicode aware, so pasted non-breaking spaces are fine too)
+        if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        }
 
         let start = i;
-        let b = bytes[i];
 
-        match b {
+        //comments are kept as trivia for the pretty printer
+        if let Some(end) = comment_end(src, i) {
+            i = end;
+            push(comment(start, end), start, end);
+            continue;
+        }
+
+        match c {
             // digits -> one numeric token (7, 11, 13, etc)
-            b'0'..=b'9' => {
+            '0'..='9' => {
                 i += 1;
-                while i < bytes.len() && is_digit(bytes[i]) {
+                while matches!(peek(i), Some('0'..='9')) {
                     i += 1;
                 }
-                let val = src[start..i].parse::<u16>().unwrap_or(0);
-                push(TokKind::Num(val), start, i, &mut pos);
+                let val = src[start..i].parse::<u16>().unwrap_or(u16::MAX); //too large: let the parser reject it
+                push(TokKind::Num(val), start, i);
+            }
+
+            //navigation words, read before note letters so "Coda" is not C + o + ...
+            _ if word_at(i).is_some() => {
+                let (word, kind) = word_at(i).unwrap();
+                i += word.len();
+                push(kind, start, i);
             }
 
             //Note letters
-            b'A'..=b'G' => {
+            'A'..='G' => {
                 i += 1;
-                push(TokKind::NoteLetter(b as char), start, i, &mut pos);
+                push(TokKind::NoteLetter(c), start, i);
             }
 
             //single-char fixed symbols
-            b'/' => {
+            '/' => {
                 i += 1;
-                push(TokKind::Slash, start, i, &mut pos);
+                push(TokKind::Slash, start, i);
             }
-            b'%' => {
+            '%' => {
                 i += 1;
-                push(TokKind::Percentage, start, i, &mut pos);
+                push(TokKind::Percentage, start, i);
             }
-            b'#' => {
-                i += 1;
-                push(TokKind::Sharp, start, i, &mut pos);
+            ':' => {
+                if starts_with_at(i, ":|") {
+                    i += if starts_with_at(i, ":||") { 3 } else { 2 };
+                    push(TokKind::RepeatEnd, start, i);
+                    //":|:" / ":||:" end one repeat and start the next
+                    if matches!(peek(i), Some(':')) {
+                        push(TokKind::RepeatStart, i, i + 1);
+                        i += 1;
+                    }
+                } else {
+                    i += 1;
+                    push(TokKind::Colon, start, i);
+                }
             }
-            b'b' => {
+            '.' => {
                 i += 1;
-                push(TokKind::Flat, start, i, &mut pos);
+                push(TokKind::Dot, start, i);
             }
-            b'-' => {
-                i += 1;
-                push(TokKind::Dash, start, i, &mut pos);
+            '#' | '♯' => {
+                i += c.len_utf8();
+                //"##" is a double sharp
+                if let Some(next @ ('#' | '♯')) = peek(i) {
+                    i += next.len_utf8();
+                    push(TokKind::DoubleSharp, start, i);
+                } else {
+                    push(TokKind::Sharp, start, i);
+                }
             }
-            b'+' => {
-                i += 1;
-                push(TokKind::Plus, start, i, &mut pos);
+            'b' | '♭' => {
+                i += c.len_utf8();
+                //"bb" is a double flat
+                if let Some(next @ ('b' | '♭')) = peek(i) {
+                    i += next.len_utf8();
+                    push(TokKind::DoubleFlat, start, i);
+                } else {
+                    push(TokKind::Flat, start, i);
+                }
+            }
+            'x' | '𝄪' => {
+                i += c.len_utf8();
+                push(TokKind::DoubleSharp, start, i);
+            }
+            '𝄫' => {
+                i += c.len_utf8();
+                push(TokKind::DoubleFlat, start, i);
             }
-            b'o' => {
+            '-' | '−' => {
+                i += c.len_utf8();
+                push(TokKind::Dash, start, i);
+            }
+            '+' => {
                 i += 1;
-                push(TokKind::LowerO, start, i, &mut pos);
+                push(TokKind::Plus, start, i);
+            }
+            'o' | '°' => {
+                i += c.len_utf8();
+                push(TokKind::LowerO, start, i);
             }
-            b'^' => {
+            '^' | '△' | 'Δ' => {
+                i += c.len_utf8();
+                push(TokKind::Caret, start, i);
+            }
+            'ø' | 'Ø' => {
+                i += c.len_utf8();
+                push(TokKind::HalfDim, start, i);
+            }
+            '(' => {
                 i += 1;
-                push(TokKind::Caret, start, i, &mut pos);
+                push(TokKind::LParen, start, i);
             }
-            b'(' => {
+            ')' => {
                 i += 1;
-                push(TokKind::LParen, start, i, &mut pos);
+                push(TokKind::RParen, start, i);
             }
-            b')' => {
+            ',' => {
                 i += 1;
-                push(TokKind::RParen, start, i, &mut pos);
+                push(TokKind::Comma, start, i);
             }
 
-            b'|' => {
-                if matches!(peek(i + 1), Some(b'|')) {
+            '|' => {
+                if starts_with_at(i, "||:") {
+                    i += 3;
+                    push(TokKind::RepeatStart, start, i);
+                } else if starts_with_at(i, "|:") {
+                    i += 2;
+                    push(TokKind::RepeatStart, start, i);
+                } else if starts_with_at(i, "||") {
                     i += 2;
+                    push(TokKind::DoubleBar, start, i);
                 } else {
                     i += 1;
+                    push(TokKind::Bar, start, i);
+                }
+            }
+
+            //section labels: "[Verse 1]" up to the closing bracket on the same line, "*A" up to the next space
+            '[' => {
+                let close = src[i..].find(|ch| ch == ']' || ch == '\n');
+                if let Some(len) = close.filter(|&len| src[i + len..].starts_with(']')) {
+                    let name = src[i + 1..i + len].trim().to_string();
+                    i += len + 1;
+                    push(TokKind::Label(name), start, i);
+                } else {
+                    i += 1;
+                    push(TokKind::Unknown('['), start, i);
+                }
+            }
+            '*' => {
+                i += 1;
+                while matches!(peek(i), Some(ch) if ch.is_alphanumeric()) {
+                    i += peek(i).unwrap().len_utf8();
+                }
+                if i - start > 1 {
+                    push(TokKind::Label(src[start + 1..i].to_string()), start, i);
+                } else {
+                    push(TokKind::Unknown('*'), start, i);
+                }
+            }
+
+            //common chord-symbol aliases: maj / M / min / m / dim / aug
+            'm' => {
+                if starts_with_at(i, "maj") || starts_with_at(i, "min") {
+                    i += 3;
+                } else {
+                    i += 1;
+                }
+                let kind = if &src[start..i] == "maj" {
+                    TokKind::Maj
+                } else {
+                    TokKind::Min
+                };
+                push(kind, start, i);
+            }
+            'M' => {
+                i += 1;
+                push(TokKind::Maj, start, i);
+            }
+            'd' | 'a' => {
+                let (word, kind) = if c == 'd' {
+                    ("dim", TokKind::Dim)
+                } else {
+                    ("aug", TokKind::Aug)
+                };
+                if starts_with_at(i, word) {
+                    i += 3;
+                    push(kind, start, i);
+                } else {
+                    i += 1;
+                    push(TokKind::Unknown(c), start, i);
                 }
-                push(TokKind::Bar, start, i, &mut pos);
             }
 
             //keywords NC / sus / no
-            b'N' => {
-                if matches!(peek(i + 1), Some(b'C')) {
+            'N' => {
+                if matches!(peek(i + 1), Some('C')) {
                     i += 2;
-                    push(TokKind::NC, start, i, &mut pos);
+                    push(TokKind::NC, start, i);
                 } else {
                     i += 1;
-                    push(TokKind::Unknown('N'), start, i, &mut pos);
+                    push(TokKind::Unknown('N'), start, i);
                 }
             }
 
-            b's' => {
+            's' => {
                 if starts_with_at(i, "sus24") {
                     i += 5;
-                    push(TokKind::Sus24, start, i, &mut pos);
+                    push(TokKind::Sus24, start, i);
                 } else if starts_with_at(i, "sus2") {
                     i += 4;
-                    push(TokKind::Sus2, start, i, &mut pos);
+                    push(TokKind::Sus2, start, i);
                 } else if starts_with_at(i, "sus4") {
                     i += 4;
-                    push(TokKind::Sus4, start, i, &mut pos);
+                    push(TokKind::Sus4, start, i);
                 } else {
                     i += 1;
-                    push(TokKind::Unknown('s'), start, i, &mut pos);
+                    push(TokKind::Unknown('s'), start, i);
                 }
             }
 
-            b'n' => {
+            'n' => {
                 if starts_with_at(i, "no35") {
                     i += 4;
-                    push(TokKind::No35, start, i, &mut pos);
+                    push(TokKind::No35, start, i);
                 } else if starts_with_at(i, "no3") {
                     i += 3;
-                    push(TokKind::No3, start, i, &mut pos);
+                    push(TokKind::No3, start, i);
                 } else if starts_with_at(i, "no5") {
                     i += 3;
-                    push(TokKind::No5, start, i, &mut pos);
+                    push(TokKind::No5, start, i);
                 } else {
                     i += 1;
-                    push(TokKind::Unknown('n'), start, i, &mut pos);
+                    push(TokKind::Unknown('n'), start, i);
                 }
             }
 
-            //anything else -> unknown
+            //anything else -> unknown, keeping the whole (possibly multi-byte) character
             _ => {
-                i += 1;
-                push(TokKind::Unknown(b as char), start, i, &mut pos);
+                i += c.len_utf8();
+                push(TokKind::Unknown(c), start, i);
             }
         }
     }
-    let end_of_file = pos;
+    let end_of_file = src.len();
     toks.push(Token {
         kind: TokKind::EOF,
         text: String::new(),
@@ -1,5 +1,11 @@
 pub mod ast;
 pub mod calc;
+pub mod chordpro;
+pub mod diag;
+pub mod expand;
 pub mod lex;
+pub mod meter;
 pub mod parse;
+pub mod pretty;
 pub mod table;
+pub mod transpose;
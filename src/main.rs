@@ -1,37 +1,187 @@
-mod ast;
-mod calc;
-mod lex;
-mod parse;
-mod table;
+use chordcalc::ast::Song;
+use chordcalc::transpose::SpellingPolicy;
+use chordcalc::{calc, chordpro, diag, expand, lex, meter, parse, pretty, table, transpose};
+
+const USAGE: &str =
+    "usage: chordcalc [--unicode] [--strict] [--count-repeats] [--by-section] <file>
+       chordcalc transpose <+/-semitones> [--sharps | --flats] [--strict] <file>
+       chordcalc fmt [--bars-per-line=N] [--align] [--unicode] [--strict] <file>
+--performed works on the bars as played, with repeats and D.C. / D.S. jumps unrolled
+--chordpro reads [Am]lyric files (implied for .cho, .crd, .chopro and .chordpro);
+  fmt and transpose write them back as ChordPro
+debugging (written to stderr): --dump-tokens, --trace-parse, --dump-ast";
+
+// How to read a chart, shared by every subcommand
+struct LoadOptions {
+    strict: bool,      //reject maj7 / m7 / dim / aug aliases
+    dump_tokens: bool, //print the token stream
+    trace_parse: bool, //print every grammar rule entered and token consumed
+    dump_ast: bool,    //print the parsed song
+    performed: bool,   //unroll repeats and jumps into the order the bars are played
+    chordpro: bool,    //read the file as ChordPro, whatever its extension
+}
 
 fn main() {
-    let path = std::env::args().nth(1).expect("usage: chordcalc <file>"); //expects 2 arguments, the second being the file to process
-    let src = std::fs::read_to_string(&path).expect("read file"); //read file
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let flag = |name: &str| args.iter().any(|a| a == name);
+    let positional: Vec<&str> = args
+        .iter()
+        .map(|a| a.as_str())
+        .filter(|a| !a.starts_with("--"))
+        .collect();
 
-    let tokens = lex::tokenize(&src);
-    if cfg!(debug_assertions) {
-        println!("=== TOKENS ===");
-        for tok in &tokens {
-            println!("{:#?}", tok);
+    let load_opts = LoadOptions {
+        strict: flag("--strict"),
+        dump_tokens: flag("--dump-tokens"),
+        trace_parse: flag("--trace-parse"),
+        dump_ast: flag("--dump-ast"),
+        performed: flag("--performed"),
+        chordpro: flag("--chordpro"),
+    };
+    let style = if flag("--unicode") {
+        table::SymbolStyle::Unicode //print chord names with ♯ ♭ − ° △
+    } else {
+        table::SymbolStyle::Ascii
+    };
+
+    match positional.as_slice() {
+        ["transpose", semitones, path] => {
+            let semitones: i8 = semitones.parse().unwrap_or_else(|_| usage());
+            let spelling = if flag("--sharps") {
+                SpellingPolicy::Sharps
+            } else if flag("--flats") {
+                SpellingPolicy::Flats
+            } else {
+                SpellingPolicy::Auto
+            };
+            let (song, _) = load(path, &load_opts);
+            let moved = transpose::transpose(&song, semitones, spelling);
+            if is_chordpro(path, &load_opts) {
+                println!("{}", chordpro::song_to_chordpro(&moved, style));
+            } else {
+                println!("{}", pretty::song_to_string(&moved));
+            }
+        }
+        ["fmt", path] => {
+            let mut opts = pretty::FormatOptions {
+                align: flag("--align"),
+                style,
+                ..Default::default()
+            };
+            if let Some(n) = args.iter().find_map(|a| a.strip_prefix("--bars-per-line=")) {
+                opts.bars_per_line = n.parse().unwrap_or_else(|_| usage());
+            }
+            let (song, _) = load(path, &load_opts);
+            if is_chordpro(path, &load_opts) {
+                // lyric sheets stay lyric sheets; the bar layout options do not apply
+                println!("{}", chordpro::song_to_chordpro(&song, style));
+            } else {
+                println!("{}", pretty::format_song(&song, &opts));
+            }
+        }
+        [path] => {
+            let (song, src) = load(path, &load_opts);
+            println!("This is a valid song");
+            let opts = table::TableOptions {
+                style,
+                count_repeats: flag("--count-repeats"), //% bars count as the bar they repeat
+                by_section: flag("--by-section"),       //one table per [Verse] / *A section
+            };
+            if let Err(d) = table::print_pitch_table_with(&song, &opts) {
+                eprintln!("{}", diag::render(&src, &d));
+                std::process::exit(1);
+            }
         }
+        _ => usage(),
     }
+}
 
-    match parse::parse_song(&tokens) {
-        Ok(song) => {
-            println!("This is a valid song");
-            if cfg!(debug_assertions) {
-                println!("\n=== AST ===");
-                println!("{:#?}", song);
+// Read and parse a chart; report every error and exit if it does not parse cleanly,
+// then print any warnings about the chords in it. The source comes back for later diagnostics
+fn load(path: &str, opts: &LoadOptions) -> (Song, String) {
+    let src = std::fs::read_to_string(path).expect("read file"); //read file
 
-                println!("\n=== Pitch Classes ===");
+    let parse_opts = parse::ParseOptions {
+        strict: opts.strict,
+    };
+    let song = if is_chordpro(path, opts) {
+        read_chordpro(&src, &parse_opts, opts)
+    } else {
+        read_chart(&src, &parse_opts, opts)
+    };
+    if opts.dump_ast {
+        eprintln!("=== AST ===");
+        eprintln!("{:#?}", song);
+    }
+    let mut warnings = meter::check_bars(&song);
+    warnings.extend(calc::analyze_song(&song));
+    for warning in warnings {
+        eprintln!("{}", diag::render(&src, &warning));
+    }
+    if opts.performed {
+        match expand::unroll(&song) {
+            Ok(played) => return (played, src),
+            Err(d) => {
+                eprintln!("{}", diag::render(&src, &d));
+                std::process::exit(1);
             }
-            calc::analyze_song(&song);
+        }
+    }
+    (song, src)
+}
 
-            table::print_pitch_table(&song);
+// A bar chart, with the token and parser-trace dumps; exits on parse errors
+fn read_chart(src: &str, parse_opts: &parse::ParseOptions, opts: &LoadOptions) -> Song {
+    let tokens = lex::tokenize(src);
+    if opts.dump_tokens {
+        eprintln!("=== TOKENS ===");
+        for tok in &tokens {
+            eprintln!("{:#?}", tok);
         }
-        Err(err) => {
-            eprintln!("\nParse error: {} at {:?}", err.msg, err.span);
-            parse::show_error_span(&src, &err.span);
+    }
+
+    let (song, errors) = if opts.trace_parse {
+        parse::parse_song_traced(&tokens, parse_opts, &mut parse::StderrTrace)
+    } else {
+        parse::parse_song_with(&tokens, parse_opts)
+    };
+    if !errors.is_empty() {
+        for err in &errors {
+            let d = diag::Diagnostic::from_parse_error(err, src);
+            eprintln!("{}", diag::render(src, &d));
         }
+        eprintln!(
+            "{} error(s); {} bar(s) parsed successfully",
+            errors.len(),
+            song.bars().count()
+        );
+        std::process::exit(1);
     }
+    song
+}
+
+fn is_chordpro(path: &str, opts: &LoadOptions) -> bool {
+    opts.chordpro || chordpro::is_chordpro_path(path)
+}
+
+// A ChordPro file; exits on errors, after printing warnings along with them.
+// There is no token stream or grammar trace to show, so asking for one is an error
+fn read_chordpro(src: &str, parse_opts: &parse::ParseOptions, opts: &LoadOptions) -> Song {
+    if opts.dump_tokens || opts.trace_parse {
+        eprintln!("--dump-tokens and --trace-parse only work on bar charts, not ChordPro");
+        std::process::exit(2);
+    }
+    let (song, diags) = chordpro::parse_chordpro(src, parse_opts);
+    for d in &diags {
+        eprintln!("{}", diag::render(src, d));
+    }
+    if diags.iter().any(|d| d.severity == diag::Severity::Error) {
+        std::process::exit(1);
+    }
+    song
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
 }
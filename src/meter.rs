@@ -0,0 +1,136 @@
+use crate::ast::{Bar, BarItem, BarLine, Meter, Song};
+use crate::diag::Diagnostic;
+
+/// The meter a chart is in until its first bar says otherwise.
+pub const DEFAULT_METER: Meter = Meter {
+    numerator: 4,
+    denominator: 4,
+};
+
+/// The meter in force before any bar states one: the header's `meter:`, or 4/4.
+pub fn start_meter(song: &Song) -> Meter {
+    song.metadata.meter.clone().unwrap_or(DEFAULT_METER)
+}
+
+/// Give every slot its start beat and duration. A meter stays in force from the bar
+/// that states it until the next one that does ([`start_meter`] before any). Slots with a written
+/// length (`C:3`, `C..`) keep it; the others share what is left of the bar equally,
+/// so `B- C#7` in 4/4 is two beats each and `C:3 D E` in 5/4 is 3 + 1 + 1.
+/// An overfull bar is left as written: its unmarked slots get no time at all.
+pub fn assign_timing(song: &mut Song) {
+    let meters = effective_meters(song);
+    for (bar, meter) in song.bars_mut().zip(meters) {
+        let written: u16 = bar.slots.iter().filter_map(|s| s.beats).sum();
+        let unmarked = bar.slots.iter().filter(|s| s.beats.is_none()).count();
+        let left = (meter.numerator as f32 - written as f32).max(0.0);
+        let share = if unmarked > 0 {
+            left / unmarked as f32
+        } else {
+            0.0
+        };
+
+        let mut start = 0.0;
+        for slot in &mut bar.slots {
+            slot.start = start;
+            slot.duration = slot.beats.map(|b| b as f32).unwrap_or(share);
+            start += slot.duration;
+        }
+    }
+}
+
+/// The meter each bar is played in, one per bar: the bar's own, or else the last one
+/// stated before it (4/4 before any).
+pub fn effective_meters(song: &Song) -> Vec<Meter> {
+    let mut meter = start_meter(song);
+    song.bars()
+        .map(|bar| {
+            if let Some(m) = &bar.meter {
+                meter = m.clone();
+            }
+            meter.clone()
+        })
+        .collect()
+}
+
+/// Warn about bars whose contents do not fit their meter: more beats written than the
+/// bar holds, fully marked bars that come up short (bars that only hold marks such as
+/// `D.S. al Fine` are not checked), chords that cannot share the bar
+/// evenly (three chords in 4/4), `%` bars that are not a plain repeat of the bar
+/// before (mixed with chords, or changing the meter), and meter changes inside a
+/// `|: ... :|` repeat, which leave the bars after the jump back in the wrong meter.
+pub fn check_bars(song: &Song) -> Vec<Diagnostic> {
+    let meters = effective_meters(song);
+    let mut warnings = Vec::new();
+    let mut repeat_meter: Option<&Meter> = None; // meter at the open "|:"
+
+    for (i, bar) in song.bars().enumerate() {
+        let meter = &meters[i];
+        let beats = meter.numerator;
+        let written: u16 = bar.slots.iter().filter_map(|s| s.beats).sum();
+        let unmarked = bar.slots.iter().filter(|s| s.beats.is_none()).count() as u16;
+        let warn = |msg: String| Diagnostic::warning(msg, bar.span.clone());
+
+        if written > beats {
+            warnings.push(warn(format!(
+                "bar has {} beats written but {} has only {}",
+                written,
+                meter_str(meter),
+                beats
+            )));
+        } else if unmarked == 0 && written < beats && !bar.slots.is_empty() {
+            warnings.push(warn(format!(
+                "bar is {} beat(s) short of {}",
+                beats - written,
+                meter_str(meter)
+            )));
+        } else if unmarked > 1 && !(beats - written).is_multiple_of(unmarked) {
+            warnings.push(
+                warn(format!(
+                    "{} chords do not share the {} beat(s) left in {} evenly",
+                    unmarked,
+                    beats - written,
+                    meter_str(meter)
+                ))
+                .with_hint("give the chords lengths, e.g. `C:2 D E`"),
+            );
+        }
+
+        if is_repeat(bar) {
+            if i > 0 && bar.meter.is_some() && meters[i - 1] != *meter {
+                warnings.push(warn(format!(
+                    "meter changes to {} on a `%` bar, but the bar it repeats is in {}",
+                    meter_str(meter),
+                    meter_str(&meters[i - 1])
+                )));
+            }
+        } else if bar.slots.iter().any(|s| s.item == BarItem::Repeat) {
+            warnings.push(warn(
+                "`%` repeats a whole bar and should stand alone".into(),
+            ));
+        }
+
+        if bar.repeat_start {
+            repeat_meter = Some(meter);
+        } else if let Some(start) = repeat_meter {
+            if bar.meter.is_some() && meter != start {
+                warnings.push(warn(format!(
+                    "meter changes to {} inside a repeat that starts in {}",
+                    meter_str(meter),
+                    meter_str(start)
+                )));
+            }
+        }
+        if bar.end == BarLine::RepeatEnd {
+            repeat_meter = None;
+        }
+    }
+    warnings
+}
+
+fn is_repeat(bar: &Bar) -> bool {
+    bar.slots.len() == 1 && bar.slots[0].item == BarItem::Repeat
+}
+
+fn meter_str(m: &Meter) -> String {
+    format!("{}/{}", m.numerator, m.denominator)
+}
@@ -1,573 +1,1307 @@
 #![allow(warnings)]
 use crate::ast::Bar as AstBar;
 use crate::ast::*;
-use crate::lex::{Span, TokKind, Token};
-use std::cell::Cell;
+use crate::lex::{tokenize, Span, TokKind, Token};
+use crate::meter::assign_timing;
+use std::fmt;
+use std::str::FromStr;
 use TokKind::*;
 
-// Global or thread-local indentation tracker
-thread_local! {
-    static INDENT: Cell<usize> = Cell::new(0);
+/// What the parser reports while it runs, when a [`TraceSink`] is attached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// A grammar rule starts, e.g. "parse_chord".
+    Enter(&'static str),
+    /// A grammar rule finished successfully.
+    Exit(&'static str),
+    /// A token was consumed.
+    Advance(TokKind),
 }
 
-// Pretty indentation string
-fn indent() -> String {
-    INDENT.with(|lvl| "  ".repeat(lvl.get()))
+/// Receives parser trace events; `depth` is how many rules are currently open.
+/// See [`parse_song_traced`].
+pub trait TraceSink {
+    fn event(&mut self, depth: usize, event: &TraceEvent);
 }
 
-// Helper: print enter/exit trace for parse functions
-fn trace_enter(name: &str) {
-    if cfg!(debug_assertions) {
-        INDENT.with(|lvl| {
-            println!("{}→ Enter {}", indent(), name);
-            lvl.set(lvl.get() + 1);
-        });
+/// Prints the trace to stderr as an indented call tree.
+#[derive(Debug, Default)]
+pub struct StderrTrace;
+
+impl TraceSink for StderrTrace {
+    fn event(&mut self, depth: usize, event: &TraceEvent) {
+        let indent = "  ".repeat(depth);
+        match event {
+            TraceEvent::Enter(rule) => eprintln!("{}→ Enter {}", indent, rule),
+            TraceEvent::Exit(rule) => eprintln!("{}← Exit {}", indent, rule),
+            TraceEvent::Advance(kind) => eprintln!("{}[advance] {:?}", indent, kind),
+        }
     }
 }
 
-fn trace_exit(name: &str) {
-    if cfg!(debug_assertions) {
-        INDENT.with(|lvl| {
-            lvl.set(lvl.get().saturating_sub(1));
-            println!("{}← Exit {}", indent(), name);
-        });
+/// Keeps every event, e.g. to inspect the parse in tests.
+#[derive(Debug, Default)]
+pub struct TraceLog {
+    pub events: Vec<TraceEvent>,
+}
+
+impl TraceSink for TraceLog {
+    fn event(&mut self, _depth: usize, event: &TraceEvent) {
+        self.events.push(event.clone());
     }
 }
 
-#[derive(Debug, Clone)]
+/// Every way a chart can fail to parse. Each kind has a stable code (see
+/// [`ErrorKind::code`]) that stays the same even if the English message changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The lexer could not make sense of this character.
+    UnknownCharacter(char),
+    ExpectedNoteLetter,
+    ExpectedNumber,
+    /// Meter numerator outside 1..=15.
+    InvalidNumerator(u16),
+    /// Meter denominator other than 1, 2, 4, 8 or 16.
+    InvalidDenominator(u16),
+    ExpectedMeterSlash,
+    /// An alteration list "(b9,#11" that is not closed.
+    ExpectedCloseParen,
+    /// An accidental that is not followed by 5, 9, 11 or 13.
+    ExpectedAlteration,
+    ExpectedExtension,
+    /// `maj`, `m`, `dim`, `aug`... with [`ParseOptions::strict`] set.
+    AliasInStrictMode(String),
+    DuplicateSus,
+    /// sus on a power chord (5) or unison (1), which have no third to replace.
+    SusWithoutThird,
+    /// Input left over after a single chord or note (`"C7 D".parse::<Chord>()`).
+    TrailingInput,
+    /// `C:0`, or more beats than any bar can hold.
+    InvalidDuration(u16),
+    /// A section label after the first chord of a bar (`C [Verse] D`).
+    LabelInsideBar,
+    /// Something other than a bar line after To Coda, Fine, D.C. or D.S.
+    ExpectedBarLine,
+    /// An ending numbered 0 or above 9.
+    InvalidVolta(u16),
+    /// "D.C. al" or "D.S. al" not followed by Coda or Fine.
+    ExpectedCodaOrFine,
+    /// A header line whose name is not title, artist, key, tempo, meter or style.
+    UnknownHeaderField(String),
+    /// A header value that does not read as its field: `key: H`, `tempo: fast`.
+    InvalidHeaderValue(String, String),
+    DuplicateHeaderField(String),
+    /// A sixth, major seventh or flat fifth on a half-diminished chord (`Cø6`, `Cø^7`,
+    /// `Cøb5`), which already has its minor seventh and flat fifth.
+    HalfDimConflict,
+}
+
+impl ErrorKind {
+    /// Stable identifier for this kind of error, suitable for looking up translations.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::UnknownCharacter(_) => "E001",
+            ErrorKind::ExpectedNoteLetter => "E002",
+            ErrorKind::ExpectedNumber => "E003",
+            ErrorKind::InvalidNumerator(_) => "E004",
+            ErrorKind::InvalidDenominator(_) => "E005",
+            ErrorKind::ExpectedMeterSlash => "E006",
+            ErrorKind::ExpectedCloseParen => "E007",
+            ErrorKind::ExpectedAlteration => "E008",
+            ErrorKind::ExpectedExtension => "E009",
+            ErrorKind::AliasInStrictMode(_) => "E010",
+            ErrorKind::DuplicateSus => "E011",
+            ErrorKind::SusWithoutThird => "E012",
+            ErrorKind::TrailingInput => "E013",
+            ErrorKind::InvalidDuration(_) => "E014",
+            ErrorKind::LabelInsideBar => "E015",
+            ErrorKind::ExpectedBarLine => "E016",
+            ErrorKind::InvalidVolta(_) => "E017",
+            ErrorKind::ExpectedCodaOrFine => "E018",
+            ErrorKind::UnknownHeaderField(_) => "E019",
+            ErrorKind::InvalidHeaderValue(..) => "E020",
+            ErrorKind::DuplicateHeaderField(_) => "E021",
+            ErrorKind::HalfDimConflict => "E022",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownCharacter(c) => write!(f, "unknown character '{}'", c),
+            ErrorKind::ExpectedNoteLetter => write!(f, "expected note letter A..G"),
+            ErrorKind::ExpectedNumber => write!(f, "expected number"),
+            ErrorKind::InvalidNumerator(n) => write!(f, "invalid numerator {} (1..=15)", n),
+            ErrorKind::InvalidDenominator(n) => {
+                write!(f, "invalid denominator {} (1,2,4,8,16)", n)
+            }
+            ErrorKind::ExpectedMeterSlash => write!(f, "expected '/' in meter"),
+            ErrorKind::ExpectedCloseParen => write!(f, "expected ')' after alt"),
+            ErrorKind::ExpectedAlteration => write!(f, "expected 5, 9, 11 or 13 after accidental"),
+            ErrorKind::ExpectedExtension => write!(f, "expected 9, 11 or 13"),
+            ErrorKind::AliasInStrictMode(alias) => {
+                write!(f, "alias '{}' is not allowed in strict mode", alias)
+            }
+            ErrorKind::DuplicateSus => write!(f, "a chord can only have one sus"),
+            ErrorKind::SusWithoutThird => {
+                write!(f, "sus cannot be used with a power chord (5) or unison (1)")
+            }
+            ErrorKind::TrailingInput => write!(f, "unexpected input after the end"),
+            ErrorKind::InvalidDuration(n) => write!(f, "invalid duration {} (1..=15 beats)", n),
+            ErrorKind::LabelInsideBar => write!(f, "a section label must start a bar"),
+            ErrorKind::ExpectedBarLine => {
+                write!(f, "expected a bar line after To Coda, Fine, D.C. or D.S.")
+            }
+            ErrorKind::InvalidVolta(n) => write!(f, "invalid ending {}. (1..=9)", n),
+            ErrorKind::ExpectedCodaOrFine => write!(f, "expected Coda or Fine after 'al'"),
+            ErrorKind::UnknownHeaderField(name) => write!(f, "unknown header field '{}'", name),
+            ErrorKind::InvalidHeaderValue(name, value) => {
+                write!(f, "invalid {} '{}'", name, value)
+            }
+            ErrorKind::DuplicateHeaderField(name) => write!(f, "'{}' is given twice", name),
+            ErrorKind::HalfDimConflict => {
+                write!(
+                    f,
+                    "half-diminished already has a minor seventh and a flat fifth"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ParseError {
-    pub msg: String,
+    pub kind: ErrorKind,
     pub span: Span,
+    /// Tokens that would have been accepted here (empty when the error is not about a
+    /// missing token, e.g. a second sus).
+    pub expected: Vec<TokKind>,
+    /// The token the parser was looking at.
+    pub found: TokKind,
+}
+
+impl ParseError {
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
 }
+
+impl std::error::Error for ParseError {}
+
 type PResult<T> = Result<T, ParseError>;
 
+// Meters the parser accepts, e.g. 3/4, 7/8, 12/16
+const NUMERATORS: [u16; 15] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const DENOMINATORS: [u16; 5] = [1, 2, 4, 8, 16];
+// An item lasts at most a whole bar of the longest meter
+const DURATIONS: [u16; 15] = NUMERATORS;
+// Tempos a header may give, in beats per minute
+const TEMPOS: std::ops::RangeInclusive<u16> = 1..=400;
+
+/// Knobs that change what the parser accepts.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Only accept the terse `-`, `+`, `o`, `^` notation and reject aliases
+    /// such as `maj7`, `m7`, `dim` or `aug`.
+    pub strict: bool,
+}
+
 pub fn parse_song(tokens: &[Token]) -> PResult<Song> {
-    let mut p = Parser {
-        toks: tokens,
-        pos: 0,
-    };
-    p.parse_song()
+    let (song, mut errors) = parse_song_recovering(tokens);
+    if errors.is_empty() {
+        Ok(song)
+    } else {
+        Err(errors.remove(0))
+    }
 }
-pub fn show_error_span(src: &str, span: &Span) {
-    let lo = span.lo as usize;
-    let hi = span.hi as usize;
-    let snippet = &src[lo.min(src.len())..hi.min(src.len())];
-    println!("Span {:?} → {:?}", span, snippet);
+
+/// Parse the whole song without stopping at the first error.
+/// A bar that fails to parse is skipped up to the next `|` and parsing resumes there,
+/// so the returned song holds every bar that did parse, alongside all the errors found.
+pub fn parse_song_recovering(tokens: &[Token]) -> (Song, Vec<ParseError>) {
+    parse_song_with(tokens, &ParseOptions::default())
+}
+
+/// Same as [`parse_song_recovering`], with explicit parser options.
+pub fn parse_song_with(tokens: &[Token], opts: &ParseOptions) -> (Song, Vec<ParseError>) {
+    let mut p = Parser::new(tokens, opts);
+    let mut song = p.parse_song();
+    assign_timing(&mut song);
+    (song, p.errors)
+}
+
+/// Same as [`parse_song_with`], reporting every rule entered and token consumed to `sink`.
+pub fn parse_song_traced(
+    tokens: &[Token],
+    opts: &ParseOptions,
+    sink: &mut dyn TraceSink,
+) -> (Song, Vec<ParseError>) {
+    let mut p = Parser::new(tokens, opts);
+    p.trace = Some(sink);
+    let mut song = p.parse_song();
+    assign_timing(&mut song);
+    (song, p.errors)
 }
+
+// "G7/B".parse::<Chord>() and friends: the whole string must be one chord / note / song
+
+impl FromStr for Chord {
+    type Err = ParseError;
+    fn from_str(s: &str) -> PResult<Chord> {
+        parse_chord_in(s, &Span { lo: 0, hi: s.len() }, &ParseOptions::default())
+    }
+}
+
+/// Parse `src[span]` as a single chord, e.g. the `Am` of a ChordPro `[Am]`.
+/// Spans in the chord and in any error point into the whole of `src`.
+pub fn parse_chord_in(src: &str, span: &Span, opts: &ParseOptions) -> PResult<Chord> {
+    let mut tokens = tokenize(&src[span.lo..span.hi]);
+    for tok in &mut tokens {
+        tok.span.lo += span.lo;
+        tok.span.hi += span.lo;
+    }
+    let mut p = Parser::new(&tokens, opts);
+    let chord = p.parse_chord()?;
+    p.expect(EOF, ErrorKind::TrailingInput)?;
+    Ok(chord)
+}
+
+impl FromStr for Note {
+    type Err = ParseError;
+    fn from_str(s: &str) -> PResult<Note> {
+        let tokens = tokenize(s);
+        let mut p = Parser::new(&tokens, &ParseOptions::default());
+        let note = p.parse_note()?;
+        p.expect(EOF, ErrorKind::TrailingInput)?;
+        Ok(note)
+    }
+}
+
+impl FromStr for Song {
+    type Err = ParseError;
+    fn from_str(s: &str) -> PResult<Song> {
+        parse_song(&tokenize(s))
+    }
+}
+// What a grammar rule returns; only a failed PResult counts as the rule failing
+trait RuleOutcome {
+    fn succeeded(&self) -> bool {
+        true
+    }
+}
+
+impl<T> RuleOutcome for PResult<T> {
+    fn succeeded(&self) -> bool {
+        self.is_ok()
+    }
+}
+
+impl RuleOutcome for Song {}
+impl RuleOutcome for SongMetadata {}
+
 struct Parser<'a> {
-    toks: &'a [Token],
+    toks: Vec<Token>,     //without comments
+    comments: Vec<Token>, //attached to bars once the song is parsed
     pos: usize,
+    errors: Vec<ParseError>, //diagnostics collected while recovering
+    opts: ParseOptions,
+    trace: Option<&'a mut dyn TraceSink>, //off unless parse_song_traced is used
+    depth: usize,                         //open rules, for trace indentation
 }
 
 impl<'a> Parser<'a> {
+    fn new(toks: &[Token], opts: &ParseOptions) -> Parser<'a> {
+        let (comments, toks) = toks
+            .iter()
+            .cloned()
+            .partition(|t| matches!(t.kind, Comment { .. }));
+        Parser {
+            toks,
+            comments,
+            pos: 0,
+            errors: Vec::new(),
+            opts: opts.clone(),
+            trace: None,
+            depth: 0,
+        }
+    }
+
+    //Tracing
+    fn trace_event(&mut self, event: TraceEvent) {
+        if let Some(sink) = self.trace.as_mut() {
+            sink.event(self.depth, &event);
+        }
+    }
+    fn trace_enter(&mut self, rule: &'static str) {
+        self.trace_event(TraceEvent::Enter(rule));
+        self.depth += 1;
+    }
+    fn trace_exit(&mut self, rule: &'static str) {
+        self.depth = self.depth.saturating_sub(1);
+        self.trace_event(TraceEvent::Exit(rule));
+    }
+    // Run one grammar rule between its Enter and Exit events, whichever way it returns.
+    // A rule that fails reports no Exit, but the depth still goes back to where it was
+    fn rule<R: RuleOutcome>(&mut self, name: &'static str, body: impl FnOnce(&mut Self) -> R) -> R {
+        let depth = self.depth;
+        self.trace_enter(name);
+        let outcome = body(self);
+        if outcome.succeeded() {
+            self.trace_exit(name);
+        } else {
+            self.depth = depth;
+        }
+        outcome
+    }
+
     //Small helpers
     fn peek(&self) -> Token {
         self.toks.get(self.pos).unwrap().clone()
     }
+    // Span of the last token consumed, used to close the span of the node being built
+    fn prev_span(&self) -> Span {
+        self.pos
+            .checked_sub(1)
+            .map(|i| self.toks[i].span.clone())
+            .unwrap_or_default()
+    }
     fn is_at_end(&self) -> bool {
         matches!(self.peek().kind, EOF)
     }
     fn advance(&mut self) {
         if !self.is_at_end() {
-            if cfg!(debug_assertions) {
-                println!("{}[advance] {:?}", indent(), self.peek().kind);
+            if self.trace.is_some() {
+                self.trace_event(TraceEvent::Advance(self.peek().kind));
             }
             self.pos += 1;
         }
     }
-    fn expect(&mut self, want: TokKind, what: &str) -> PResult<()> {
+    // Aliases (maj7, m7, dim, aug...) are rejected in strict mode
+    fn check_alias(&self, t: &Token) -> PResult<()> {
+        if self.opts.strict {
+            Err(self.error_at(t, ErrorKind::AliasInStrictMode(t.text.clone()), vec![]))
+        } else {
+            Ok(())
+        }
+    }
+    fn expect(&mut self, want: TokKind, kind: ErrorKind) -> PResult<()> {
         if self.peek().kind == want {
             self.advance();
             Ok(())
         } else {
-            Err(ParseError {
-                msg: what.into(),
-                span: self.peek().span.clone(),
-            })
+            Err(self.error(kind, vec![want]))
+        }
+    }
+    // Error at the current token
+    fn error(&self, kind: ErrorKind, expected: Vec<TokKind>) -> ParseError {
+        self.error_at(&self.peek(), kind, expected)
+    }
+    // A token the lexer did not recognise is reported as such, whatever we were looking for
+    fn error_at(&self, t: &Token, kind: ErrorKind, expected: Vec<TokKind>) -> ParseError {
+        let kind = match t.kind {
+            Unknown(c) => ErrorKind::UnknownCharacter(c),
+            _ => kind,
+        };
+        ParseError {
+            kind,
+            span: t.span.clone(),
+            expected,
+            found: t.kind.clone(),
         }
     }
 
-    //song ::= bar {bar} "|"
-    fn parse_song(&mut self) -> PResult<Song> {
-        trace_enter("parse_song");
-        let mut bars = Vec::new();
+    //song ::= {[label] ["|:"] bar barline}
+    //barline ::= "|" | "||" | ":|" , and "|:" both closes a bar and opens the next
+    fn parse_song(&mut self) -> Song {
+        self.rule("parse_song", |p| {
+            let metadata = p.parse_header();
+            let mut sections = Vec::new();
+
+            while !p.is_at_end() {
+                let parsed = p.parse_section_bar(&mut sections);
+                let end = match p.peek().kind {
+                    DoubleBar => BarLine::Double,
+                    RepeatEnd => BarLine::RepeatEnd,
+                    _ => BarLine::Single,
+                };
+                if parsed {
+                    let bar = sections.last_mut().and_then(|s| s.bars.last_mut()).unwrap();
+                    bar.end = end;
+                }
+                // a "|:" is left for the next bar to open with
+                if matches!(p.peek().kind, Bar | DoubleBar | RepeatEnd) {
+                    p.advance();
+                }
+            }
 
-        if self.is_at_end() {
-            return Ok(Song { bars });
+            // however the chart stops, it ends with a final bar line
+            let last = sections.iter_mut().rev().find_map(|s| s.bars.last_mut());
+            if let Some(bar) = last {
+                if bar.end == BarLine::Single {
+                    bar.end = BarLine::Double;
+                }
+            }
+            let mut song = Song { metadata, sections };
+            p.attach_comments(&mut song);
+            song
+        })
+    }
+
+    // A comment on a line of its own goes above the label or bar that follows it, any
+    // other after the bar it follows. Comments with no such bar take the nearest one.
+    fn attach_comments(&self, song: &mut Song) {
+        for tok in &self.comments {
+            let Comment { text, own_line } = &tok.kind else {
+                continue;
+            };
+            // a comment above the header stays above it
+            let header = self.toks.iter().find(|t| matches!(t.kind, Header { .. }));
+            if *own_line && header.is_some_and(|h| h.span.lo > tok.span.lo) {
+                song.metadata.comments.push(text.clone());
+                continue;
+            }
+            // labels open sections in order, so the nth label token is the nth label;
+            // the header is printed first, so comments in it belong to what follows it
+            let next = self
+                .toks
+                .iter()
+                .position(|t| t.span.lo > tok.span.lo && !matches!(t.kind, Header { .. }));
+            if let Some(next) = next.filter(|&n| *own_line && matches!(self.toks[n].kind, Label(_)))
+            {
+                let nth = self.toks[..next]
+                    .iter()
+                    .filter(|t| matches!(t.kind, Label(_)))
+                    .count();
+                let mut labelled = song.sections.iter_mut().filter(|s| s.label.is_some());
+                if let Some(section) = labelled.nth(nth) {
+                    section.comments.push(text.clone());
+                    continue;
+                }
+            }
+
+            let mut bars: Vec<&mut AstBar> = song.bars_mut().collect();
+            let next = bars.iter().position(|b| b.span.lo > tok.span.lo);
+            let prev = bars.iter().rposition(|b| b.span.lo < tok.span.lo);
+            match (own_line, next, prev) {
+                (true, Some(i), _) | (false, Some(i), None) => {
+                    bars[i].comments_before.push(text.clone())
+                }
+                (_, _, Some(i)) => bars[i].comments_after.push(text.clone()),
+                (_, None, None) => {} // a chart with no bars has nowhere to keep them
+            }
         }
-        bars.push(self.parse_bar()?);
+    }
+
+    //header ::= {name ":" value newline}
+    //A bad line is reported and skipped; the rest of the header and the chart still parse
+    fn parse_header(&mut self) -> SongMetadata {
+        self.rule("parse_header", |p| {
+            let mut meta = SongMetadata::default();
+            while let Header { name, value } = p.peek().kind {
+                let tok = p.peek();
+                p.advance();
+                // the value ends the header line, trimmed
+                let value_lo = tok.span.hi - value.len();
+                let result = set_header_field(&mut meta, &name, &value, value_lo);
+                if let Err(kind) = result {
+                    let err = p.error_at(&tok, kind, vec![]);
+                    p.errors.push(err);
+                }
+            }
+            meta
+        })
+    }
 
-        while self.peek().kind == TokKind::Bar {
+    // A label opens a new section; bars before the first label go in one without a label.
+    // Returns whether a bar was added.
+    fn parse_section_bar(&mut self, sections: &mut Vec<Section>) -> bool {
+        while let Label(name) = self.peek().kind {
             self.advance();
-            if !matches!(self.peek().kind, Bar | EOF) {
-                bars.push(self.parse_bar()?);
+            sections.push(Section {
+                label: Some(name),
+                comments: Vec::new(),
+                bars: Vec::new(),
+            });
+        }
+        if sections.is_empty() {
+            sections.push(Section {
+                label: None,
+                comments: Vec::new(),
+                bars: Vec::new(),
+            });
+        }
+        let repeat_start = self.peek().kind == RepeatStart;
+        if repeat_start {
+            self.advance();
+        }
+        // "[Outro] ||" is a section with no bars yet
+        if self.at_bar_line() {
+            return false;
+        }
+        let bars = &mut sections.last_mut().unwrap().bars;
+        let parsed = self.parse_bar_recovering(bars);
+        if parsed {
+            bars.last_mut().unwrap().repeat_start = repeat_start;
+        }
+        parsed
+    }
+
+    fn at_bar_line(&self) -> bool {
+        matches!(
+            self.peek().kind,
+            Bar | DoubleBar | RepeatStart | RepeatEnd | EOF
+        )
+    }
+
+    // Parse one bar; on error record it and resynchronise at the next bar line (or EOF)
+    fn parse_bar_recovering(&mut self, bars: &mut Vec<AstBar>) -> bool {
+        match self.parse_bar() {
+            Ok(bar) => {
+                bars.push(bar);
+                true
+            }
+            Err(err) => {
+                self.errors.push(err);
+                while !self.at_bar_line() {
+                    self.advance();
+                }
+                false
             }
         }
-        trace_exit("parse_song");
-        Ok(Song { bars })
     }
 
-    // bar := [meter] chords "|"
+    // bar := {volta} {segno | coda} [meter] {slot} {end_mark}
+    // slot := chords [duration]
     fn parse_bar(&mut self) -> PResult<AstBar> {
-        trace_enter("parse_bar");
-        let meter = self.parse_meter_opt()?;
-        let mut items = Vec::new();
+        self.rule("parse_bar", |p| {
+            let start = p.peek().span;
+            let volta = p.parse_volta_opt()?;
+            let mut marks = Vec::new();
+            while matches!(p.peek().kind, Segno | Coda) {
+                marks.push(if p.peek().kind == Segno {
+                    NavMark::Segno
+                } else {
+                    NavMark::Coda
+                });
+                p.advance();
+            }
+            let meter = p.parse_meter_opt()?;
+            let mut slots = Vec::new();
 
-        while !matches!(self.peek().kind, TokKind::Bar | EOF) {
-            items.push(self.parse_bar_item()?);
-        }
-        trace_exit("parse_bar");
-        Ok(AstBar { meter, items })
+            while !p.at_bar_line() && !p.at_end_mark() {
+                if let Label(_) = p.peek().kind {
+                    return Err(p.error(ErrorKind::LabelInsideBar, vec![]));
+                }
+                let item = p.parse_bar_item()?;
+                let beats = p.parse_duration_opt()?;
+                // timing is filled in once the meter of every bar is known
+                slots.push(Slot {
+                    item,
+                    beats,
+                    start: 0.0,
+                    duration: 0.0,
+                    lyric: None,
+                });
+            }
+            while p.at_end_mark() {
+                marks.push(p.parse_end_mark()?);
+            }
+            if !p.at_bar_line() {
+                let expected = vec![Bar, DoubleBar, RepeatStart, RepeatEnd];
+                return Err(p.error(ErrorKind::ExpectedBarLine, expected));
+            }
+            Ok(AstBar {
+                meter,
+                slots,
+                span: start.to(&p.prev_span()),
+                repeat_start: false,
+                end: BarLine::Single,
+                volta,
+                marks,
+                comments_before: Vec::new(),
+                comments_after: Vec::new(),
+            })
+        })
     }
 
-    //meter ::= numerator "/" denominator
-    fn parse_meter_opt(&mut self) -> PResult<Option<Meter>> {
-        trace_enter("parse_meter_opt");
-        if let Num(_) = self.peek().kind {
-            let num = self.read_num_in(1..=15, "invalid numerator (1..=15")?;
-            self.expect(Slash, "expected '/' in meter")?;
-            let den = self.read_num_set(&[1, 2, 4, 8, 16], "invalid denominator (1,2,4,8,16)")?;
-            trace_exit("parse_meter_opt");
-            Ok(Some(Meter {
-                numerator: num as u16,
-                denominator: den as u16,
-            }))
-        } else {
-            trace_exit("parse_meter_opt");
-            Ok(None)
-        }
+    //volta ::= number "."   e.g. the "2." of a second ending, or "1. 2." of a shared one
+    fn parse_volta_opt(&mut self) -> PResult<Vec<u8>> {
+        self.rule("parse_volta_opt", |p| {
+            let mut numbers = Vec::new();
+            while let Num(n) = p.peek().kind {
+                if p.toks.get(p.pos + 1).map(|t| &t.kind) != Some(&Dot) {
+                    break;
+                }
+                if !(1..=9).contains(&n) {
+                    let endings = (1..=9).map(Num).collect();
+                    return Err(p.error(ErrorKind::InvalidVolta(n), endings));
+                }
+                p.advance();
+                p.advance();
+                numbers.push(n as u8);
+            }
+            Ok(numbers)
+        })
     }
 
-    fn read_num_in(&mut self, range: std::ops::RangeInclusive<u16>, msg: &str) -> PResult<u16> {
-        let t = self.peek();
-        if let Num(n) = t.kind {
-            if range.contains(&n) {
-                self.advance();
-                Ok(n)
+    fn at_end_mark(&self) -> bool {
+        matches!(self.peek().kind, ToCoda | Fine | DaCapo | DalSegno)
+    }
+
+    //end_mark ::= "To Coda" | "Fine" | ("D.C." | "D.S.") ["al" ("Coda" | "Fine")]
+    fn parse_end_mark(&mut self) -> PResult<NavMark> {
+        self.rule("parse_end_mark", |p| {
+            let kind = p.peek().kind;
+            p.advance();
+            let until = if matches!(kind, DaCapo | DalSegno) && p.peek().kind == Al {
+                p.advance();
+                let until = match p.peek().kind {
+                    Coda => Until::Coda,
+                    Fine => Until::Fine,
+                    _ => return Err(p.error(ErrorKind::ExpectedCodaOrFine, vec![Coda, Fine])),
+                };
+                p.advance();
+                Some(until)
             } else {
-                Err(ParseError {
-                    msg: msg.into(),
-                    span: t.span.clone(),
-                })
-            }
-        } else {
-            Err(ParseError {
-                msg: "expected number".into(),
-                span: t.span.clone(),
+                None
+            };
+            Ok(match kind {
+                ToCoda => NavMark::ToCoda,
+                Fine => NavMark::Fine,
+                DaCapo => NavMark::DaCapo(until),
+                _ => NavMark::DalSegno(until),
             })
-        }
+        })
     }
 
-    fn read_num_set(&mut self, set: &[u16], msg: &str) -> PResult<u16> {
-        let t = self.peek();
-        if let Num(n) = t.kind {
-            if set.contains(&n) {
+    //duration ::= ":" beats | "." {"."}
+    //each dot adds a beat to the one the item starts on, so "C.." and "C:3" are the same
+    fn parse_duration_opt(&mut self) -> PResult<Option<u16>> {
+        self.rule("parse_duration_opt", |p| {
+            let beats = match p.peek().kind {
+                Colon => {
+                    p.advance();
+                    Some(p.read_num(&DURATIONS, ErrorKind::InvalidDuration)?)
+                }
+                Dot => {
+                    let mut beats = 1;
+                    while p.peek().kind == Dot {
+                        p.advance();
+                        beats += 1;
+                    }
+                    Some(beats)
+                }
+                _ => None,
+            };
+            Ok(beats)
+        })
+    }
+
+    //meter ::= numerator "/" denominator
+    fn parse_meter_opt(&mut self) -> PResult<Option<Meter>> {
+        self.rule("parse_meter_opt", |p| {
+            if let Num(_) = p.peek().kind {
+                let num = p.read_num(&NUMERATORS, ErrorKind::InvalidNumerator)?;
+                p.expect(Slash, ErrorKind::ExpectedMeterSlash)?;
+                let den = p.read_num(&DENOMINATORS, ErrorKind::InvalidDenominator)?;
+                Ok(Some(Meter {
+                    numerator: num as u16,
+                    denominator: den as u16,
+                }))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    // Read a number that must be one of `allowed`
+    fn read_num(&mut self, allowed: &[u16], invalid: fn(u16) -> ErrorKind) -> PResult<u16> {
+        let expected = allowed.iter().map(|n| Num(*n)).collect();
+        match self.peek().kind {
+            Num(n) if allowed.contains(&n) => {
                 self.advance();
                 Ok(n)
-            } else {
-                Err(ParseError {
-                    msg: msg.into(),
-                    span: t.span.clone(),
-                })
             }
-        } else {
-            Err(ParseError {
-                msg: "expected number".into(),
-                span: t.span.clone(),
-            })
+            Num(n) => Err(self.error(invalid(n), expected)),
+            _ => Err(self.error(ErrorKind::ExpectedNumber, expected)),
         }
     }
 
     //chords ::= NC | % | chord {chord}
     fn parse_bar_item(&mut self) -> PResult<BarItem> {
-        trace_enter("parse_bar_item");
-        match self.peek().kind {
+        self.rule("parse_bar_item", |p| match p.peek().kind {
             NC => {
-                self.advance();
-                trace_exit("parse_bar_item");
+                p.advance();
                 Ok(BarItem::NC)
             }
             Percentage => {
-                self.advance();
-                trace_exit("parse_bar_item");
+                p.advance();
                 Ok(BarItem::Repeat)
             }
-            _ => {
-                trace_exit("parse_bar_item");
-                Ok(BarItem::Chord(self.parse_chord()?))
-            }
-        }
+            _ => Ok(BarItem::Chord(p.parse_chord()?)),
+        })
     }
 
     //chord ::= root [description] [bass] , and root := note, and bass ::= note "/"
     fn parse_chord(&mut self) -> PResult<Chord> {
-        trace_enter("parse_chord");
-        let root = self.parse_note()?;
-        let description = self.parse_description_opt()?;
-        let bass = if self.peek().kind == Slash {
-            self.advance();
-            Some(self.parse_note()?)
-        } else {
-            None
-        };
-
-        if let Some(desc) = &description {
-            if desc.qual.is_some() && desc.sus.is_some() {
-                return Err(ParseError {
-                    msg: "qual and sus cannot coexist".into(),
-                    span: self.peek().span.clone(),
-                });
-            }
-        }
-        trace_exit("parse_chord");
-        Ok(Chord {
-            root,
-            description,
-            bass,
+        self.rule("parse_chord", |p| {
+            let start = p.peek().span;
+            let root = p.parse_note()?;
+            let description = p.parse_description_opt()?;
+            let bass = if p.peek().kind == Slash {
+                p.advance();
+                Some(p.parse_note()?)
+            } else {
+                None
+            };
+            Ok(Chord {
+                root,
+                description,
+                bass,
+                span: start.to(&p.prev_span()),
+            })
         })
     }
 
     //note ::= letter [acc]
+    //acc ::= "#" | "b" | "x" | "##" | "bb"
     fn parse_note(&mut self) -> PResult<Note> {
-        trace_enter("parse_note");
-        let letter = self.parse_letter()?;
-        let acc = self.parse_acc_opt()?;
-        trace_exit("parse_note");
-        Ok(Note { letter, acc })
+        self.rule("parse_note", |p| {
+            let start = p.peek().span;
+            let letter = p.parse_letter()?;
+            let acc = p.parse_acc_opt()?;
+            Ok(Note {
+                letter,
+                acc,
+                span: start.to(&p.prev_span()),
+            })
+        })
     }
 
     fn parse_letter(&mut self) -> PResult<Letter> {
-        trace_enter("parse_letter");
-        let t = self.peek();
-        let l = match t.kind {
-            NoteLetter('A') => Letter::A,
-            NoteLetter('B') => Letter::B,
-            NoteLetter('C') => Letter::C,
-            NoteLetter('D') => Letter::D,
-            NoteLetter('E') => Letter::E,
-            NoteLetter('F') => Letter::F,
-            NoteLetter('G') => Letter::G,
-            _ => {
-                return Err(ParseError {
-                    msg: "expected note letter A..G".into(),
-                    span: t.span.clone(),
-                });
-            }
-        };
-        self.advance();
-        trace_exit("parse_letter");
-        Ok(l)
+        self.rule("parse_letter", |p| {
+            let t = p.peek();
+            let l = match t.kind {
+                NoteLetter('A') => Letter::A,
+                NoteLetter('B') => Letter::B,
+                NoteLetter('C') => Letter::C,
+                NoteLetter('D') => Letter::D,
+                NoteLetter('E') => Letter::E,
+                NoteLetter('F') => Letter::F,
+                NoteLetter('G') => Letter::G,
+                _ => {
+                    let letters = "ABCDEFG".chars().map(NoteLetter).collect();
+                    return Err(p.error_at(&t, ErrorKind::ExpectedNoteLetter, letters));
+                }
+            };
+            p.advance();
+            Ok(l)
+        })
     }
 
     fn parse_acc_opt(&mut self) -> PResult<Option<Accidental>> {
-        trace_enter("parse_acc_opt");
-        let acc = self.peek();
-        let a = match acc.kind {
-            Sharp => {
-                self.advance();
-                Some(Accidental::Sharp)
-            }
-            Flat => {
-                self.advance();
-                Some(Accidental::Flat)
-            }
-            _ => None,
-        };
-        trace_exit("parse_acc_opt");
-        Ok(a)
+        self.rule("parse_acc_opt", |p| {
+            let acc = p.peek();
+            let a = match acc.kind {
+                Sharp => {
+                    p.advance();
+                    Some(Accidental::Sharp)
+                }
+                Flat => {
+                    p.advance();
+                    Some(Accidental::Flat)
+                }
+                DoubleSharp => {
+                    p.advance();
+                    Some(Accidental::DoubleSharp)
+                }
+                DoubleFlat => {
+                    p.advance();
+                    Some(Accidental::DoubleFlat)
+                }
+                _ => None,
+            };
+            Ok(a)
+        })
     }
 
-    //description ::= [qual] [qnum] [add] [sus] [omit]
+    //description ::= [qual] [sus] [qnum] {add} [sus {add}] [omit]   (at most one sus)
     fn parse_description_opt(&mut self) -> PResult<Option<Description>> {
-        trace_enter("parse_description_opt");
-        let t = self.peek();
-        // Only start a description if the next token can begin one
-        if !matches!(
-            t.kind,
-            TokKind::Dash
-                | TokKind::Plus
-                | TokKind::LowerO
-                | TokKind::Num(_)
-                | TokKind::Caret
-                | TokKind::LParen
-                | TokKind::Sus2
-                | TokKind::Sus4
-                | TokKind::Sus24
-                | TokKind::No3
-                | TokKind::No5
-                | TokKind::No35
-        ) {
-            trace_exit("parse_description_opt");
-            return Ok(None);
-        }
-        let qual = self.parse_qual_opt()?;
-        let qnum = self.parse_qnum_opt()?;
-        let add = self.parse_add_opt()?;
-        let sus = self.parse_sus_opt()?;
-        let omit = self.parse_omit_opt()?;
-
-        if qual.is_none() && qnum.is_none() && add.is_none() && sus.is_none() && omit.is_none() {
-            trace_exit("parse_description_opt");
-            return Ok(None);
-        }
-        trace_exit("parse_description_opt");
-        Ok(Some(Description {
-            qual,
-            qnum,
-            add,
-            sus,
-            omit,
-        }))
-    }
-
-    //qual ::= "-" | "+" | "o" | "5" | "1"
-    fn parse_qual_opt(&mut self) -> PResult<Option<Qual>> {
-        trace_enter("parse_qual_opt");
-        let qual = self.peek();
-        let q = match qual.kind {
-            TokKind::Dash => {
-                self.advance();
-                Some(Qual::Minus)
+        self.rule("parse_description_opt", |p| {
+            let t = p.peek();
+            // Only start a description if the next token can begin one
+            if !matches!(
+                t.kind,
+                TokKind::Dash
+                    | TokKind::Plus
+                    | TokKind::LowerO
+                    | TokKind::HalfDim
+                    | TokKind::Maj
+                    | TokKind::Min
+                    | TokKind::Dim
+                    | TokKind::Aug
+                    | TokKind::Num(_)
+                    | TokKind::Caret
+                    | TokKind::LParen
+                    | TokKind::Sus2
+                    | TokKind::Sus4
+                    | TokKind::Sus24
+                    | TokKind::No3
+                    | TokKind::No5
+                    | TokKind::No35
+            ) {
+                return Ok(None);
             }
-            TokKind::Plus => {
-                self.advance();
-                Some(Qual::Plus)
+            let mut qual = p.parse_qual_opt()?;
+            // sus may come straight after the quality ("Csus4 7") or after the
+            // number, before or after the additions ("C7sus4", "C13(b9)sus4",
+            // "C7sus4(b9)"), but only once
+            let mut sus_tok = p.peek();
+            let mut sus = p.parse_sus_opt()?;
+            let qnum_tok = p.peek();
+            let mut qnum = p.parse_qnum_opt()?;
+            let add_tok = p.peek();
+            let mut add = p.parse_adds()?;
+            let late = p.peek();
+            if let Some(late_sus) = p.parse_sus_opt()? {
+                if sus.is_some() {
+                    return Err(p.error_at(&late, ErrorKind::DuplicateSus, vec![]));
+                }
+                sus = Some(late_sus);
+                sus_tok = late;
+                add.extend(p.parse_adds()?);
             }
-            TokKind::LowerO => {
-                self.advance();
-                Some(Qual::LowerO)
+            let omit = p.parse_omit_opt()?;
+
+            // sus replaces the third, so it needs a quality that has one
+            if sus.is_some() && matches!(qual, Some(Qual::Five) | Some(Qual::One)) {
+                return Err(p.error_at(&sus_tok, ErrorKind::SusWithoutThird, vec![]));
             }
-            TokKind::Num(5) => {
-                self.advance();
-                Some(Qual::Five)
+
+            // "ø" spells its own seventh and fifth, so "Cø6", "Cø^7" and "Cøb5" have no
+            // long spelling that reads back as the same chord
+            if qual == Some(Qual::HalfDim) {
+                if qnum.as_ref().is_some_and(|q| q.hat || q.n == Some(6)) {
+                    return Err(p.error_at(&qnum_tok, ErrorKind::HalfDimConflict, vec![]));
+                }
+                if add.contains(&Add::Acc5(Some(Accidental::Flat))) {
+                    return Err(p.error_at(&add_tok, ErrorKind::HalfDimConflict, vec![]));
+                }
             }
-            TokKind::Num(1) => {
-                self.advance();
-                Some(Qual::One)
+
+            // Half-diminished is always a seventh chord: "ø" and "ø7" are the same chord,
+            // and "-7b5" / "m7b5" are just the long spelling of it, as "-9b5" and
+            // "-11b5" are of ø9 and ø11 (a minor sixth or major seventh is not)
+            if qual == Some(Qual::HalfDim) {
+                qnum.get_or_insert(Qnum {
+                    hat: false,
+                    n: Some(7),
+                    ext: None,
+                });
+            } else if qual == Some(Qual::Minus)
+                && qnum.as_ref().is_some_and(|q| !q.hat && q.n != Some(6))
+                && add.contains(&Add::Acc5(Some(Accidental::Flat)))
+            {
+                qual = Some(Qual::HalfDim);
+                add.retain(|a| *a != Add::Acc5(Some(Accidental::Flat)));
             }
-            _ => None,
-        };
-        trace_exit("parse_qual_opt");
-        Ok(q)
+
+            if qual.is_none() && qnum.is_none() && add.is_empty() && sus.is_none() && omit.is_none()
+            {
+                return Ok(None);
+            }
+            Ok(Some(Description {
+                qual,
+                qnum,
+                add,
+                sus,
+                omit,
+            }))
+        })
+    }
+
+    //qual ::= "-" | "+" | "o" | "ø" | "5" | "1"
+    //aliases: "m" | "min" -> "-", "dim" -> "o", "aug" -> "+"
+    fn parse_qual_opt(&mut self) -> PResult<Option<Qual>> {
+        self.rule("parse_qual_opt", |p| {
+            let qual = p.peek();
+            let q = match qual.kind {
+                TokKind::Dash => {
+                    p.advance();
+                    Some(Qual::Minus)
+                }
+                TokKind::Plus => {
+                    p.advance();
+                    Some(Qual::Plus)
+                }
+                TokKind::LowerO => {
+                    p.advance();
+                    Some(Qual::LowerO)
+                }
+                TokKind::HalfDim => {
+                    p.advance();
+                    Some(Qual::HalfDim)
+                }
+                TokKind::Min => {
+                    p.check_alias(&qual)?;
+                    p.advance();
+                    Some(Qual::Minus)
+                }
+                TokKind::Dim => {
+                    p.check_alias(&qual)?;
+                    p.advance();
+                    Some(Qual::LowerO)
+                }
+                TokKind::Aug => {
+                    p.check_alias(&qual)?;
+                    p.advance();
+                    Some(Qual::Plus)
+                }
+                TokKind::Num(5) => {
+                    p.advance();
+                    Some(Qual::Five)
+                }
+                TokKind::Num(1) => {
+                    p.advance();
+                    Some(Qual::One)
+                }
+                _ => None,
+            };
+            Ok(q)
+        })
     }
 
-    //qnum ::= "6" | [" ^ "] "7" | [" ^ "] ext
+    //qnum ::= "6" | [" ^ "] "7" | [" ^ "] ext      ("maj" / "M" may stand in for "^")
     fn parse_qnum_opt(&mut self) -> PResult<Option<Qnum>> {
-        trace_enter("parse_qnum_opt");
-        let t = self.peek();
-        let mut hat = false;
+        self.rule("parse_qnum_opt", |p| {
+            let t = p.peek();
+            let mut hat = false;
 
-        // Case "6"
-        if matches!(t.kind, TokKind::Num(6)) {
-            self.advance();
-            trace_exit("parse_qnum_opt");
-            return Ok(Some(Qnum {
-                hat,
-                n: Some(6),
-                ext: None,
-            }));
-        }
+            // Case "6"
+            if matches!(t.kind, TokKind::Num(6)) {
+                p.advance();
+                return Ok(Some(Qnum {
+                    hat,
+                    n: Some(6),
+                    ext: None,
+                }));
+            }
 
-        // Case starts with '^'
-        if matches!(t.kind, TokKind::Caret) {
-            // Peek ahead before consuming
-            let next = self.toks.get(self.pos + 1).cloned();
-
-            // If there’s no next or it's not 7/9/11/13 -> do not advance
-            if let Some(next) = next {
-                match next.kind {
-                    TokKind::Num(7) => {
-                        self.advance(); // consume '^'
-                        self.advance(); // consume 7
-                        trace_exit("parse_qnum_opt");
+            // "maj" / "M" read like '^'; on their own they just spell a major triad,
+            // and "maj6" / "M6" the major sixth chord "6"
+            if matches!(t.kind, TokKind::Maj) {
+                p.check_alias(&t)?;
+                match p.toks.get(p.pos + 1).map(|n| n.kind.clone()) {
+                    Some(TokKind::Num(7 | 9 | 11 | 13)) => {}
+                    Some(TokKind::Num(6)) => {
+                        p.advance(); // consume "maj"
+                        p.advance(); // consume 6
                         return Ok(Some(Qnum {
-                            hat: true,
-                            n: Some(7),
+                            hat,
+                            n: Some(6),
                             ext: None,
                         }));
                     }
-                    TokKind::Num(9) | TokKind::Num(11) | TokKind::Num(13) => {
-                        self.advance(); // consume '^'
-                        let ext = self.parse_ext()?;
-                        trace_exit("parse_qnum_opt");
-                        return Ok(Some(Qnum {
-                            hat: true,
-                            n: None,
-                            ext: Some(ext),
-                        }));
-                    }
                     _ => {
-                        trace_exit("parse_qnum_opt");
+                        p.advance(); // consume "maj"
                         return Ok(None);
-                    } // leave position unchanged
+                    }
                 }
             }
-            trace_exit("parse_qnum_opt");
-            return Ok(None); // '^' at EOF
-        } else {
-            if matches!(t.kind, TokKind::Num(7)) {
-                self.advance(); //consume 7
-                return Ok(Some(Qnum {
-                    hat: false,
-                    n: Some(7),
-                    ext: None,
-                }));
-            } else if matches!(t.kind, TokKind::Num(9)) {
-                self.advance(); //consume 9
-                return Ok(Some(Qnum {
-                    hat: false,
-                    n: Some(9),
-                    ext: None,
-                }));
-            } else if matches!(t.kind, TokKind::Num(11)) {
-                self.advance(); //consume 11
-                return Ok(Some(Qnum {
-                    hat: false,
-                    n: Some(11),
-                    ext: None,
-                }));
-            } else if matches!(t.kind, TokKind::Num(13)) {
-                self.advance(); //consume 13
-                return Ok(Some(Qnum {
-                    hat: false,
-                    n: Some(13),
-                    ext: None,
-                }));
-            }
-        }
-        trace_exit("parse_qnum_opt");
-        Ok(None)
-    }
 
-    // add := alt | "(" alt ")"
-    // alt := [acc] "5" | [acc] ext
-    fn parse_add_opt(&mut self) -> PResult<Option<Add>> {
-        trace_enter("parse_add_opt");
-        if matches!(self.peek().kind, TokKind::LParen) {
-            self.advance();
-            let alt = self.parse_alt()?;
-            self.expect(TokKind::RParen, "expected ')' after alt")?;
-            trace_exit("parse_add_opt");
-            return Ok(Some(alt));
-        };
+            // Case starts with '^' (or "maj")
+            if matches!(t.kind, TokKind::Caret | TokKind::Maj) {
+                // Peek ahead before consuming
+                let next = p.toks.get(p.pos + 1).cloned();
 
-        if (matches!(self.peek().kind, TokKind::Flat)
-            || matches!(self.peek().kind, TokKind::Sharp)
-            || matches!(self.peek().kind, TokKind::Num(5))
-            || matches!(self.peek().kind, TokKind::Num(9))
-            || matches!(self.peek().kind, TokKind::Num(11))
-            || matches!(self.peek().kind, TokKind::Num(13)))
-        {
-            let alt = self.parse_alt()?;
-            trace_exit("parse_add_opt");
-            return Ok(Some(alt));
-        }
+                // If there’s no next or it's not 7/9/11/13 -> do not advance
+                if let Some(next) = next {
+                    match next.kind {
+                        TokKind::Num(7) => {
+                            p.advance(); // consume '^'
+                            p.advance(); // consume 7
+                            return Ok(Some(Qnum {
+                                hat: true,
+                                n: Some(7),
+                                ext: None,
+                            }));
+                        }
+                        TokKind::Num(9) | TokKind::Num(11) | TokKind::Num(13) => {
+                            p.advance(); // consume '^'
+                            let ext = p.parse_ext()?;
+                            return Ok(Some(Qnum {
+                                hat: true,
+                                n: None,
+                                ext: Some(ext),
+                            }));
+                        }
+                        _ => {
+                            return Ok(None);
+                        } // leave position unchanged
+                    }
+                }
+                return Ok(None); // '^' at EOF
+            } else {
+                if matches!(t.kind, TokKind::Num(7)) {
+                    p.advance(); //consume 7
+                    return Ok(Some(Qnum {
+                        hat: false,
+                        n: Some(7),
+                        ext: None,
+                    }));
+                } else if matches!(t.kind, TokKind::Num(9)) {
+                    p.advance(); //consume 9
+                    return Ok(Some(Qnum {
+                        hat: false,
+                        n: Some(9),
+                        ext: None,
+                    }));
+                } else if matches!(t.kind, TokKind::Num(11)) {
+                    p.advance(); //consume 11
+                    return Ok(Some(Qnum {
+                        hat: false,
+                        n: Some(11),
+                        ext: None,
+                    }));
+                } else if matches!(t.kind, TokKind::Num(13)) {
+                    p.advance(); //consume 13
+                    return Ok(Some(Qnum {
+                        hat: false,
+                        n: Some(13),
+                        ext: None,
+                    }));
+                }
+            }
+            Ok(None)
+        })
+    }
 
-        trace_exit("parse_add_opt");
-        return Ok(None);
+    // adds := { add }
+    // add  := alt | "(" alt {"," alt} ")"
+    // alt  := [acc] "5" | [acc] ext
+    fn parse_adds(&mut self) -> PResult<Vec<Add>> {
+        self.rule("parse_adds", |p| {
+            let mut adds = Vec::new();
+            loop {
+                if matches!(p.peek().kind, TokKind::LParen) {
+                    p.advance();
+                    adds.push(p.parse_alt()?);
+                    while matches!(p.peek().kind, TokKind::Comma) {
+                        p.advance();
+                        adds.push(p.parse_alt()?);
+                    }
+                    if p.peek().kind != TokKind::RParen {
+                        let expected = vec![TokKind::Comma, TokKind::RParen];
+                        return Err(p.error(ErrorKind::ExpectedCloseParen, expected));
+                    }
+                    p.advance();
+                } else if matches!(
+                    p.peek().kind,
+                    TokKind::Flat
+                        | TokKind::Sharp
+                        | TokKind::DoubleFlat
+                        | TokKind::DoubleSharp
+                        | TokKind::Num(5)
+                        | TokKind::Num(9)
+                        | TokKind::Num(11)
+                        | TokKind::Num(13)
+                ) {
+                    adds.push(p.parse_alt()?);
+                } else {
+                    break;
+                }
+            }
+            Ok(adds)
+        })
     }
 
     //alt := [acc] "5" | [acc] ext
     fn parse_alt(&mut self) -> PResult<Add> {
-        trace_enter("parse_alt");
-        let acc = self.parse_acc_opt()?;
+        self.rule("parse_alt", |p| {
+            let acc = p.parse_acc_opt()?;
 
-        let t = self.peek();
+            let t = p.peek();
 
-        if matches!(t.kind, TokKind::Num(5)) {
-            self.advance();
-            trace_exit("parse_alt");
-            return Ok(Add::Acc5(acc));
-        }
+            if matches!(t.kind, TokKind::Num(5)) {
+                p.advance();
+                return Ok(Add::Acc5(acc));
+            }
 
-        if matches!(t.kind, TokKind::Num(9))
-            || matches!(t.kind, TokKind::Num(11))
-            || matches!(t.kind, TokKind::Num(13))
-        {
-            let ext = self.parse_ext()?;
-            trace_exit("parse_alt");
-            return Ok(Add::AccExt(acc, ext));
-        }
-        return Err(ParseError {
-            msg: "Expected 5 or ext after acc".into(),
-            span: t.span.clone(),
-        });
+            if matches!(t.kind, TokKind::Num(9))
+                || matches!(t.kind, TokKind::Num(11))
+                || matches!(t.kind, TokKind::Num(13))
+            {
+                let ext = p.parse_ext()?;
+                return Ok(Add::AccExt(acc, ext));
+            }
+            return Err(p.error_at(
+                &t,
+                ErrorKind::ExpectedAlteration,
+                vec![Num(5), Num(9), Num(11), Num(13)],
+            ));
+        })
     }
 
     //ext := "9" | "11" | "13"
     fn parse_ext(&mut self) -> PResult<Ext> {
-        trace_enter("parse_ext");
-        let t = self.peek();
+        self.rule("parse_ext", |p| {
+            let t = p.peek();
 
-        let n = match t.kind {
-            TokKind::Num(9) => {
-                self.advance();
-                Ext::Nine
-            }
-            TokKind::Num(11) => {
-                self.advance();
-                Ext::Eleven
-            }
-            TokKind::Num(13) => {
-                self.advance();
-                Ext::Thirteen
-            }
-            _ => {
-                return Err(ParseError {
-                    msg: "expected 9, 11 or 13".into(),
-                    span: t.span.clone(),
-                });
-            }
-        };
-        trace_exit("parse_ext");
-        Ok(n)
+            let n = match t.kind {
+                TokKind::Num(9) => {
+                    p.advance();
+                    Ext::Nine
+                }
+                TokKind::Num(11) => {
+                    p.advance();
+                    Ext::Eleven
+                }
+                TokKind::Num(13) => {
+                    p.advance();
+                    Ext::Thirteen
+                }
+                _ => {
+                    return Err(p.error_at(
+                        &t,
+                        ErrorKind::ExpectedExtension,
+                        vec![Num(9), Num(11), Num(13)],
+                    ));
+                }
+            };
+            Ok(n)
+        })
     }
 
     //sus := sus2 | sus4 | sus24
     fn parse_sus_opt(&mut self) -> PResult<Option<Sus>> {
-        trace_enter("parse_sus_opt");
-        let t = self.peek();
+        self.rule("parse_sus_opt", |p| {
+            let t = p.peek();
 
-        let sus = match t.kind {
-            TokKind::Sus2 => {
-                self.advance();
-                Some(Sus::Sus2)
-            }
-            TokKind::Sus4 => {
-                self.advance();
-                Some(Sus::Sus4)
-            }
-            TokKind::Sus24 => {
-                self.advance();
-                Some(Sus::Sus24)
-            }
-            _ => None,
-        };
-        trace_exit("parse_sus_opt");
-        Ok(sus)
+            let sus = match t.kind {
+                TokKind::Sus2 => {
+                    p.advance();
+                    Some(Sus::Sus2)
+                }
+                TokKind::Sus4 => {
+                    p.advance();
+                    Some(Sus::Sus4)
+                }
+                TokKind::Sus24 => {
+                    p.advance();
+                    Some(Sus::Sus24)
+                }
+                _ => None,
+            };
+            Ok(sus)
+        })
     }
 
     //omit := "no3" | "no5" | "no35"
     fn parse_omit_opt(&mut self) -> PResult<Option<Omit>> {
-        trace_enter("parse_omit_opt");
-        let t = self.peek();
+        self.rule("parse_omit_opt", |p| {
+            let t = p.peek();
 
-        let om = match t.kind {
-            TokKind::No3 => {
-                self.advance();
-                Some(Omit::No3)
-            }
-            TokKind::No5 => {
-                self.advance();
-                Some(Omit::No5)
-            }
-            TokKind::No35 => {
-                self.advance();
-                Some(Omit::No35)
-            }
-            _ => None,
-        };
-        trace_exit("parse_omit_opt");
-        Ok(om)
+            let om = match t.kind {
+                TokKind::No3 => {
+                    p.advance();
+                    Some(Omit::No3)
+                }
+                TokKind::No5 => {
+                    p.advance();
+                    Some(Omit::No5)
+                }
+                TokKind::No35 => {
+                    p.advance();
+                    Some(Omit::No35)
+                }
+                _ => None,
+            };
+            Ok(om)
+        })
+    }
+}
+
+// Header values
+
+/// Check `value` and store it as the header field `name` (`key`, `tempo`...) of `meta`.
+/// `value_lo` is where the value starts in the source, so the key's span points into it.
+/// Also used for the `{key: ...}` style directives of ChordPro files.
+pub(crate) fn set_header_field(
+    meta: &mut SongMetadata,
+    name: &str,
+    value: &str,
+    value_lo: usize,
+) -> Result<(), ErrorKind> {
+    let invalid = || ErrorKind::InvalidHeaderValue(name.to_string(), value.to_string());
+    match name {
+        "title" => set_field(&mut meta.title, value.to_string(), name),
+        "artist" => set_field(&mut meta.artist, value.to_string(), name),
+        "style" => set_field(&mut meta.style, value.to_string(), name),
+        "key" => match parse_key(value, value_lo) {
+            Some(key) => set_field(&mut meta.key, key, name),
+            None => Err(invalid()),
+        },
+        "tempo" => match value.parse::<u16>() {
+            Ok(bpm) if TEMPOS.contains(&bpm) => set_field(&mut meta.tempo, bpm, name),
+            _ => Err(invalid()),
+        },
+        "meter" => match parse_header_meter(value) {
+            Some(meter) => set_field(&mut meta.meter, meter, name),
+            None => Err(invalid()),
+        },
+        _ => Err(ErrorKind::UnknownHeaderField(name.to_string())),
+    }
+}
+
+fn set_field<T>(field: &mut Option<T>, value: T, name: &str) -> Result<(), ErrorKind> {
+    if field.is_some() {
+        return Err(ErrorKind::DuplicateHeaderField(name.to_string()));
     }
+    *field = Some(value);
+    Ok(())
+}
+
+// Key suffixes: the chord aliases, and the words they stand for; longest first
+const KEY_SUFFIXES: [(&str, bool); 7] = [
+    ("minor", true),
+    ("major", false),
+    ("min", true),
+    ("maj", false),
+    ("m", true),
+    ("-", true),
+    ("M", false),
+];
+
+// "E", "F#m", "Bb-", "C#min", "Cmaj", "C major"; the value starts at `lo` in the source
+fn parse_key(value: &str, lo: usize) -> Option<Key> {
+    let (tonic, minor) = KEY_SUFFIXES
+        .iter()
+        .find_map(|(suffix, minor)| value.strip_suffix(suffix).map(|t| (t, *minor)))
+        .unwrap_or((value, false));
+    let mut tonic = tonic.trim_end().parse::<Note>().ok()?;
+    tonic.span.lo += lo;
+    tonic.span.hi += lo;
+    Some(Key { tonic, minor })
+}
+
+// "6/8", with the same numerators and denominators a bar accepts
+fn parse_header_meter(value: &str) -> Option<Meter> {
+    let (num, den) = value.split_once('/')?;
+    let numerator = num.trim().parse().ok().filter(|n| NUMERATORS.contains(n))?;
+    let denominator = den
+        .trim()
+        .parse()
+        .ok()
+        .filter(|d| DENOMINATORS.contains(d))?;
+    Some(Meter {
+        numerator,
+        denominator,
+    })
 }
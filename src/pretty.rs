@@ -0,0 +1,231 @@
+use crate::ast::{Bar, BarItem, BarLine, Chord, NavMark, Song, SongMetadata, Until};
+use crate::lex::hash_is_sharp;
+use crate::table::{chord_to_string, SymbolStyle};
+use std::fmt;
+
+/// Layout choices for [`format_song`]. Whatever the options, the output parses
+/// back to the same song.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Start a new line after this many bars; 0 keeps each section on one line.
+    pub bars_per_line: usize,
+    /// Pad bars so the bar lines of consecutive lines sit in the same columns.
+    pub align: bool,
+    pub style: SymbolStyle,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            bars_per_line: 4,
+            align: false,
+            style: SymbolStyle::Ascii,
+        }
+    }
+}
+
+/// Emit a song back as chart text with the default layout.
+pub fn song_to_string(song: &Song) -> String {
+    format_song(song, &FormatOptions::default())
+}
+
+/// Emit a song back as canonical chart text: meters in front of the bar they start,
+/// bars separated by their bar lines (`|`, `||`, `|:`, `:|`), `bars_per_line` bars per
+/// line and a closing `||`.
+/// Each labelled section starts on a new line under its `[label]`, and the header,
+/// if any, comes first. Comments stay with the bar they were written above or after.
+pub fn format_song(song: &Song, opts: &FormatOptions) -> String {
+    let mut header: String = song
+        .metadata
+        .comments
+        .iter()
+        .map(|c| format!("{}\n", c))
+        .collect();
+    header.push_str(&header_to_string(&song.metadata));
+    if song
+        .sections
+        .iter()
+        .all(|s| s.label.is_none() && s.bars.is_empty())
+    {
+        return header.trim_end().to_string();
+    }
+    let sections: Vec<(&Option<String>, Vec<String>)> = song
+        .sections
+        .iter()
+        .map(|section| {
+            let cells = section
+                .bars
+                .iter()
+                .map(|bar| bar_to_string(bar, opts.style))
+                .collect();
+            (&section.label, cells)
+        })
+        .collect();
+
+    let per_line = if opts.bars_per_line == 0 {
+        let longest = sections.iter().map(|(_, cells)| cells.len()).max();
+        longest.unwrap_or(0).max(1)
+    } else {
+        opts.bars_per_line
+    };
+
+    // widest bar in each column, counted in characters so Unicode symbols line up
+    let mut widths = vec![0; per_line];
+    if opts.align {
+        for (_, cells) in &sections {
+            for (i, cell) in cells.iter().enumerate() {
+                let col = i % per_line;
+                widths[col] = widths[col].max(cell.chars().count());
+            }
+        }
+    }
+
+    // a line break needs no separator of its own: "... | D |\nE | ..."
+    let total = song.bars().count();
+    let mut seen = 0;
+    let mut out = header;
+    for (section, (label, cells)) in song.sections.iter().zip(&sections) {
+        if let Some(label) = label {
+            for comment in &section.comments {
+                out.push_str(comment);
+                out.push('\n');
+            }
+            out.push_str(&format!("[{}]\n", label));
+        }
+        let bars: Vec<(&Bar, &String)> = section.bars.iter().zip(cells).collect();
+        for line in bars.chunks(per_line) {
+            for (col, (bar, cell)) in line.iter().enumerate() {
+                seen += 1;
+                // comments above a bar start it on a new line
+                for comment in &bar.comments_before {
+                    out.push_str(comment);
+                    out.push('\n');
+                }
+                if (col == 0 || !bar.comments_before.is_empty()) && bar.repeat_start {
+                    out.push_str("|: ");
+                }
+                out.push_str(&format!("{:<w$} ", cell, w = widths[col]));
+                let next = line
+                    .get(col + 1)
+                    .map(|(next, _)| *next)
+                    .filter(|next| next.comments_before.is_empty());
+                out.push_str(&bar_line(bar, next, seen == total));
+                // "# ..." and "// ..." run to the end of the line, "/* ... */" need not
+                let mut ends_line = false;
+                for comment in &bar.comments_after {
+                    // "#2nd ending" after a bar would read as a sharp, so it keeps its own line
+                    let own_line = comment.strip_prefix('#').is_some_and(hash_is_sharp);
+                    out.push(if ends_line || own_line { '\n' } else { ' ' });
+                    out.push_str(comment);
+                    ends_line = !comment.starts_with("/*");
+                }
+                out.push(if next.is_some() && !ends_line {
+                    ' '
+                } else {
+                    '\n'
+                });
+            }
+        }
+    }
+
+    if out.ends_with("]\n") {
+        out.push_str("||"); // ends on a label with no bars
+    }
+    out.trim_end().to_string()
+}
+
+/// The header lines of a chart, `name: value` each, in a fixed order and ending in a
+/// newline. Empty when the song has no metadata.
+pub fn header_to_string(meta: &SongMetadata) -> String {
+    let fields = [
+        ("title", meta.title.clone()),
+        ("artist", meta.artist.clone()),
+        ("key", meta.key.as_ref().map(|k| k.to_string())),
+        ("tempo", meta.tempo.map(|t| t.to_string())),
+        (
+            "meter",
+            meta.meter
+                .as_ref()
+                .map(|m| format!("{}/{}", m.numerator, m.denominator)),
+        ),
+        ("style", meta.style.clone()),
+    ];
+    fields
+        .iter()
+        .filter_map(|(name, value)| Some(format!("{}: {}\n", name, value.as_ref()?)))
+        .collect()
+}
+
+// The bar line after `bar`, including the "|:" that opens `next` when both are on one line
+fn bar_line(bar: &Bar, next: Option<&Bar>, last: bool) -> String {
+    let line = match bar.end {
+        BarLine::Single if last => "||",
+        BarLine::Single => "|",
+        BarLine::Double => "||",
+        BarLine::RepeatEnd => ":|",
+    };
+    match next {
+        Some(next) if next.repeat_start && line == "|" => "|:".to_string(),
+        Some(next) if next.repeat_start && line == ":|" => ":|:".to_string(),
+        Some(next) if next.repeat_start => format!("{} |:", line),
+        _ => line.to_string(),
+    }
+}
+
+fn bar_to_string(bar: &Bar, style: SymbolStyle) -> String {
+    let mut parts = Vec::new();
+    parts.extend(bar.volta.iter().map(|n| format!("{}.", n)));
+    let (before, after): (Vec<NavMark>, Vec<NavMark>) = bar
+        .marks
+        .iter()
+        .partition(|m| matches!(m, NavMark::Segno | NavMark::Coda));
+    parts.extend(before.iter().map(mark_str));
+    if let Some(m) = &bar.meter {
+        parts.push(format!("{}/{}", m.numerator, m.denominator));
+    }
+    for slot in &bar.slots {
+        let mut part = match &slot.item {
+            BarItem::NC => "NC".to_string(),
+            BarItem::Repeat => "%".to_string(),
+            BarItem::Chord(ch) => chord_to_string(ch, style),
+        };
+        // written lengths come back as "C:3" whether they were typed that way or as "C.."
+        if let Some(beats) = slot.beats {
+            part.push_str(&format!(":{}", beats));
+        }
+        parts.push(part);
+    }
+    parts.extend(after.iter().map(mark_str));
+    parts.join(" ")
+}
+
+fn mark_str(mark: &NavMark) -> String {
+    let until = |u: &Option<Until>| match u {
+        Some(Until::Coda) => " al Coda",
+        Some(Until::Fine) => " al Fine",
+        None => "",
+    };
+    match mark {
+        NavMark::Segno => "Segno".to_string(),
+        NavMark::Coda => "Coda".to_string(),
+        NavMark::ToCoda => "To Coda".to_string(),
+        NavMark::Fine => "Fine".to_string(),
+        NavMark::DaCapo(u) => format!("D.C.{}", until(u)),
+        NavMark::DalSegno(u) => format!("D.S.{}", until(u)),
+    }
+}
+
+// Display prints chart syntax that parses back (see the FromStr impls in parse.rs).
+// Note's Display lives next to the type in ast.rs.
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", chord_to_string(self, SymbolStyle::Ascii))
+    }
+}
+
+impl fmt::Display for Song {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", song_to_string(self))
+    }
+}
@@ -1,10 +1,102 @@
-use crate::ast::{BarItem, Chord, Song};
+use crate::ast::{Accidental, BarItem, Chord, Song};
 use crate::calc::chord_to_pitch_classes;
+use crate::diag::Diagnostic;
+use crate::expand::expand_repeats;
+use crate::lex::Span;
+use crate::pretty::header_to_string;
+
+/// Which symbols to use when spelling chords back out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymbolStyle {
+    /// `#`, `b`, `-`, `o`, `^` as typed in chart files
+    #[default]
+    Ascii,
+    /// `♯`, `♭`, `−`, `°`, `△` as printed on lead sheets
+    Unicode,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TableOptions {
+    pub style: SymbolStyle,
+    /// Count `%` bars as the chords they repeat (see [`crate::expand::expand_repeats`])
+    /// instead of skipping them.
+    pub count_repeats: bool,
+    /// Print one table, with its own totals, per section of the song.
+    pub by_section: bool,
+}
 
 pub fn print_pitch_table(song: &Song) {
+    print_pitch_table_with(song, &TableOptions::default())
+        .expect("without count_repeats there is nothing to expand");
+}
+
+/// One chord row of the pitch-class table.
+#[derive(Debug, Clone)]
+pub struct TableRow {
+    pub index: usize,   // 1-based chord number
+    pub section: usize, // index into `song.sections`
+    pub name: String,
+    pub pcs: Vec<u8>,
+    pub span: Span, // where the chord is in the source, for highlighting
+}
+
+/// The rows of the pitch-class table, one per chord. NC is skipped, and so is `%`
+/// unless `opts.count_repeats` is set. Counting repeats fails, as
+/// [`crate::expand::expand_repeats`] does, when the first bar is a `%`.
+pub fn pitch_table_rows(song: &Song, opts: &TableOptions) -> Result<Vec<TableRow>, Diagnostic> {
+    if opts.count_repeats {
+        return Ok(chord_rows(&expand_repeats(song)?, opts));
+    }
+    Ok(chord_rows(song, opts))
+}
+
+fn chord_rows(song: &Song, opts: &TableOptions) -> Vec<TableRow> {
+    song.sections
+        .iter()
+        .enumerate()
+        .flat_map(|(section, s)| s.bars.iter().map(move |bar| (section, bar)))
+        .flat_map(|(section, bar)| bar.slots.iter().map(move |slot| (section, slot)))
+        .filter_map(|(section, slot)| match &slot.item {
+            BarItem::Chord(ch) => Some((section, ch)),
+            BarItem::Repeat | BarItem::NC => None,
+        })
+        .enumerate()
+        .map(|(i, (section, ch))| TableRow {
+            index: i + 1,
+            section,
+            name: chord_to_string(ch, opts.style),
+            pcs: chord_to_pitch_classes(ch),
+            span: ch.span.clone(),
+        })
+        .collect()
+}
+
+/// Print the song's header, if it has one, then the table of its chords.
+/// Nothing is printed if the rows cannot be built (see [`pitch_table_rows`]).
+pub fn print_pitch_table_with(song: &Song, opts: &TableOptions) -> Result<(), Diagnostic> {
+    let rows = pitch_table_rows(song, opts)?;
+    let header = header_to_string(&song.metadata);
+    if !header.is_empty() {
+        println!("{}", header);
+    }
+    if !opts.by_section {
+        print_rows(&rows);
+        return Ok(());
+    }
+    for (i, section) in song.sections.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("[{}]", section.label.as_deref().unwrap_or("no label"));
+        let in_section: Vec<TableRow> = rows.iter().filter(|r| r.section == i).cloned().collect();
+        print_rows(&in_section);
+    }
+    Ok(())
+}
+
+fn print_rows(rows: &[TableRow]) {
     let headers = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B"];
     let mut totals = [0u32; 12];
-    let mut chord_index = 1;
 
     const COL_WIDTH: usize = 3; // width for each pitch-class column
 
@@ -22,33 +114,23 @@ pub fn print_pitch_table(song: &Song) {
     }
     println!();
 
-    for bar in &song.bars {
-        for item in &bar.items {
-            match item {
-                BarItem::Chord(ch) => {
-                    let pcs = chord_to_pitch_classes(ch);
-                    let mut row = [" "; 12];
-
-                    for p in pcs {
-                        let idx = (p % 12) as usize;
-                        row[idx] = "*";
-                        totals[idx] += 1;
-                    }
-
-                    let name = chord_to_string(ch);
-                    print!("{:>3}.", chord_index);
-
-                    // Pitch-class columns
-                    for cell in row {
-                        print!("{:>COL_WIDTH$}", cell, COL_WIDTH = COL_WIDTH);
-                    }
-                    // Chord name
-                    println!("  {}", name);
-                    chord_index += 1;
-                }
-                BarItem::Repeat | BarItem::NC => continue,
-            }
+    for chord_row in rows {
+        let mut row = [" "; 12];
+
+        for &p in &chord_row.pcs {
+            let idx = (p % 12) as usize;
+            row[idx] = "*";
+            totals[idx] += 1;
         }
+
+        print!("{:>3}.", chord_row.index);
+
+        // Pitch-class columns
+        for cell in row {
+            print!("{:>COL_WIDTH$}", cell, COL_WIDTH = COL_WIDTH);
+        }
+        // Chord name
+        println!("  {}", chord_row.name);
     }
 
     // Bottom separator
@@ -66,15 +148,14 @@ pub fn print_pitch_table(song: &Song) {
     println!();
 }
 
-fn chord_to_string(ch: &Chord) -> String {
+/// Spell a chord back in chart syntax, using the given symbol style.
+pub fn chord_to_string(ch: &Chord, style: SymbolStyle) -> String {
+    let unicode = style == SymbolStyle::Unicode;
     let mut s = ch.root.letter.to_string();
 
     // Root accidental
     if let Some(acc) = &ch.root.acc {
-        match acc {
-            crate::ast::Accidental::Sharp => s.push('#'),
-            crate::ast::Accidental::Flat => s.push('b'),
-        }
+        s.push_str(acc_str(*acc, style));
     }
 
     if let Some(desc) = &ch.description {
@@ -82,9 +163,10 @@ fn chord_to_string(ch: &Chord) -> String {
         if let Some(q) = &desc.qual {
             use crate::ast::Qual::*;
             match q {
-                Minus => s.push('-'),
+                Minus => s.push(if unicode { '−' } else { '-' }),
                 Plus => s.push('+'),
-                LowerO => s.push('o'),
+                LowerO => s.push(if unicode { '°' } else { 'o' }),
+                HalfDim => s.push(if unicode { 'ø' } else { '-' }),
                 Five => s.push('5'),
                 One => s.push('1'),
             }
@@ -92,8 +174,16 @@ fn chord_to_string(ch: &Chord) -> String {
 
         // Chord number / extension (qnum)
         if let Some(qn) = &desc.qnum {
+            // "C5 7" needs the space, "C57" would read as a single number
+            let digit_qual = matches!(
+                desc.qual,
+                Some(crate::ast::Qual::Five) | Some(crate::ast::Qual::One)
+            );
+            if digit_qual && !qn.hat {
+                s.push(' ');
+            }
             if qn.hat {
-                s.push('^');
+                s.push(if unicode { '△' } else { '^' });
             }
             if let Some(n) = qn.n {
                 s.push_str(&n.to_string());
@@ -108,7 +198,37 @@ fn chord_to_string(ch: &Chord) -> String {
             }
         }
 
-        // Suspensions (sus2, sus4, sus24)
+        // ASCII has no 'ø', so half-diminished is spelled out as -7b5
+        if desc.qual == Some(crate::ast::Qual::HalfDim) && !unicode {
+            if desc.qnum.is_none() {
+                s.push('7');
+            }
+            s.push_str("b5");
+        }
+
+        // Additions like (9), (#11), grouped as (b9,#11)
+        if !desc.add.is_empty() {
+            use crate::ast::{Add, Ext};
+            let alts: Vec<String> = desc
+                .add
+                .iter()
+                .map(|add| {
+                    let (acc, degree) = match add {
+                        Add::AccExt(acc, Ext::Nine) => (acc, "9"),
+                        Add::AccExt(acc, Ext::Eleven) => (acc, "11"),
+                        Add::AccExt(acc, Ext::Thirteen) => (acc, "13"),
+                        Add::Acc5(acc) => (acc, "5"),
+                    };
+                    let acc = acc.map(|a| acc_str(a, style)).unwrap_or("");
+                    format!("{}{}", acc, degree)
+                })
+                .collect();
+            s.push('(');
+            s.push_str(&alts.join(","));
+            s.push(')');
+        }
+
+        // Suspensions (sus2, sus4, sus24), after the additions as the parser expects
         if let Some(sus) = &desc.sus {
             use crate::ast::Sus::*;
             match sus {
@@ -118,38 +238,6 @@ fn chord_to_string(ch: &Chord) -> String {
             }
         }
 
-        // Parenthesized additions like (9), (#11), etc.
-        if let Some(add) = &desc.add {
-            use crate::ast::{Accidental, Add, Ext};
-            match add {
-                Add::AccExt(acc, ext) => {
-                    s.push('(');
-                    match acc {
-                        Some(Accidental::Sharp) => s.push('#'),
-                        Some(Accidental::Flat) => s.push('b'),
-                        None => {}
-                    }
-                    match ext {
-                        Ext::Nine => s.push('9'),
-                        Ext::Eleven => s.push_str("11"),
-                        Ext::Thirteen => s.push_str("13"),
-                    }
-                    s.push(')');
-                }
-                Add::Acc5(acc) => {
-                    s.push('(');
-                    if let Some(a) = acc {
-                        match a {
-                            Accidental::Sharp => s.push('#'),
-                            Accidental::Flat => s.push('b'),
-                        }
-                    }
-                    s.push('5');
-                    s.push(')');
-                }
-            }
-        }
-
         // Omit rules (no3, no5, no35)
         if let Some(omit) = &desc.omit {
             use crate::ast::Omit::*;
@@ -166,12 +254,22 @@ fn chord_to_string(ch: &Chord) -> String {
         s.push('/');
         s.push_str(&bass.letter.to_string());
         if let Some(acc) = &bass.acc {
-            match acc {
-                crate::ast::Accidental::Sharp => s.push('#'),
-                crate::ast::Accidental::Flat => s.push('b'),
-            }
+            s.push_str(acc_str(*acc, style));
         }
     }
 
     s
 }
+
+fn acc_str(acc: Accidental, style: SymbolStyle) -> &'static str {
+    match (acc, style) {
+        (Accidental::Sharp, SymbolStyle::Ascii) => "#",
+        (Accidental::Flat, SymbolStyle::Ascii) => "b",
+        (Accidental::Sharp, SymbolStyle::Unicode) => "♯",
+        (Accidental::Flat, SymbolStyle::Unicode) => "♭",
+        (Accidental::DoubleSharp, SymbolStyle::Ascii) => "x",
+        (Accidental::DoubleFlat, SymbolStyle::Ascii) => "bb",
+        (Accidental::DoubleSharp, SymbolStyle::Unicode) => "𝄪",
+        (Accidental::DoubleFlat, SymbolStyle::Unicode) => "𝄫",
+    }
+}
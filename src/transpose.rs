@@ -0,0 +1,87 @@
+use crate::ast::*;
+use crate::calc::{note_to_pc, plain_spelling};
+
+/// How transposed roots and bass notes are spelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpellingPolicy {
+    /// Follow the target key: flats in F, Bb, Eb, Ab and Db (and their relative
+    /// minors), sharps otherwise. The key is the header's `key:`, or else the song's
+    /// first chord.
+    #[default]
+    Auto,
+    Sharps,
+    Flats,
+}
+
+// Major keys written with flats, as pitch classes: F, Bb, Eb, Ab, Db
+const FLAT_KEYS: [u8; 5] = [5, 10, 3, 8, 1];
+
+/// Move every chord root and bass note of `song` by `semitones` (negative goes down).
+/// Chord qualities, numbers and additions are left untouched; a declared key moves too.
+pub fn transpose(song: &Song, semitones: i8, spelling: SpellingPolicy) -> Song {
+    let flats = match spelling {
+        SpellingPolicy::Sharps => false,
+        SpellingPolicy::Flats => true,
+        SpellingPolicy::Auto => match &song.metadata.key {
+            Some(key) => FLAT_KEYS.contains(&relative_major(&key.tonic, key.minor, semitones)),
+            None => first_chord(song)
+                .map(|ch| FLAT_KEYS.contains(&major_key_of(ch, semitones)))
+                .unwrap_or(false),
+        },
+    };
+
+    let mut out = song.clone();
+    if let Some(key) = &mut out.metadata.key {
+        key.tonic = transpose_note(&key.tonic, semitones, flats);
+    }
+    for bar in out.bars_mut() {
+        for slot in &mut bar.slots {
+            if let BarItem::Chord(ch) = &mut slot.item {
+                ch.root = transpose_note(&ch.root, semitones, flats);
+                if let Some(bass) = &ch.bass {
+                    ch.bass = Some(transpose_note(bass, semitones, flats));
+                }
+            }
+        }
+    }
+    out
+}
+
+// The new note keeps the span of the one it replaces
+fn transpose_note(note: &Note, semitones: i8, flats: bool) -> Note {
+    Note {
+        span: note.span.clone(),
+        ..plain_spelling(shift(note_to_pc(note), semitones), flats)
+    }
+}
+
+fn shift(pc: u8, semitones: i8) -> u8 {
+    (pc as i16 + semitones as i16).rem_euclid(12) as u8
+}
+
+fn first_chord(song: &Song) -> Option<&Chord> {
+    song.bars()
+        .flat_map(|bar| bar.slots.iter())
+        .find_map(|slot| match &slot.item {
+            BarItem::Chord(ch) => Some(ch),
+            _ => None,
+        })
+}
+
+// The major key the transposed chord would be the tonic of (minor chords use their relative major)
+fn major_key_of(ch: &Chord, semitones: i8) -> u8 {
+    let minor = matches!(
+        ch.description.as_ref().and_then(|d| d.qual),
+        Some(Qual::Minus) | Some(Qual::HalfDim) | Some(Qual::LowerO)
+    );
+    relative_major(&ch.root, minor, semitones)
+}
+
+fn relative_major(tonic: &Note, minor: bool, semitones: i8) -> u8 {
+    let tonic = shift(note_to_pc(tonic), semitones);
+    if minor {
+        (tonic + 3) % 12
+    } else {
+        tonic
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(warnings)]
 use chordcalc::ast;
 use chordcalc::calc;
+use chordcalc::diag;
 use chordcalc::lex;
 use chordcalc::parse;
 use chordcalc::table;
@@ -72,7 +73,8 @@ fn run_test(
         Err(err) => {
             println!("\x1b[31m❌ FAIL: {}\x1b[0m", file_name);
             //eprintln!("\nParse error: {} at {:?}", err.msg, err.span);
-            parse::show_error_span(&input_text, &err.span);
+            let d = diag::Diagnostic::from_parse_error(&err, &input_text);
+            print!("{}", diag::render(&input_text, &d));
             *failed += 1;
         }
     }
@@ -105,7 +107,8 @@ fn run_calc(
         Err(err) => {
             println!("\x1b[31m❌ FAIL: {}\x1b[0m", file_name);
             //eprintln!("\nParse error: {} at {:?}", err.msg, err.span);
-            parse::show_error_span(&input_text, &err.span);
+            let d = diag::Diagnostic::from_parse_error(&err, &input_text);
+            print!("{}", diag::render(&input_text, &d));
             *failed += 1;
         }
     }
@@ -116,3 +119,1061 @@ fn pc(indices: &[u8]) -> Vec<u8> {
     v.sort();
     v
 }
+
+// The chords of a chart in playing order; NC and % are skipped
+fn parse_chords(src: &str) -> Result<Vec<ast::Chord>, parse::ParseError> {
+    let song = parse::parse_song(&lex::tokenize(src))?;
+    Ok(song
+        .bars()
+        .flat_map(|bar| &bar.slots)
+        .filter_map(|slot| match &slot.item {
+            ast::BarItem::Chord(ch) => Some(ch.clone()),
+            _ => None,
+        })
+        .collect())
+}
+
+fn chords(src: &str) -> Vec<ast::Chord> {
+    parse_chords(src).unwrap()
+}
+
+#[test]
+fn parser_recovers_and_reports_every_bad_bar() {
+    let src = "4/4 C | D-7 X | E- | F^7 )( | G7 ||";
+    let tokens = lex::tokenize(src);
+    let (song, errors) = parse::parse_song_recovering(&tokens);
+
+    assert_eq!(errors.len(), 2);
+    // C, E- and G7 survive; the two broken bars are dropped
+    assert_eq!(song.bars().count(), 3);
+    assert!(parse::parse_song(&tokens).is_err());
+}
+
+#[test]
+fn diagnostics_point_at_line_and_column() {
+    let src = "4/4 C | D |\nE su4 | F ||";
+    let tokens = lex::tokenize(src);
+    let err = parse::parse_song(&tokens).unwrap_err();
+    let d = diag::Diagnostic::from_parse_error(&err, src);
+
+    assert_eq!(diag::line_col(src, err.span.lo), (2, 3));
+    assert_eq!(d.hint.as_deref(), Some("did you mean `sus4`?"));
+
+    let out = diag::render(src, &d);
+    assert!(out.contains("line 2, column 3"));
+    assert!(out.contains("2 | E su4 | F ||"));
+    assert!(out.contains("  |   ^\n"));
+}
+
+#[test]
+fn parse_errors_carry_kind_code_and_expected_tokens() {
+    use lex::TokKind;
+    use parse::ErrorKind;
+
+    let err = parse::parse_song(&lex::tokenize("4/3 C ||")).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidDenominator(3));
+    assert_eq!(err.code(), "E005");
+    assert_eq!(err.found, TokKind::Num(3));
+    assert!(err.expected.contains(&TokKind::Num(8)));
+
+    // unrecognised characters are reported as such, whatever the parser expected
+    let err = parse::parse_song(&lex::tokenize("C | ? ||")).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::UnknownCharacter('?'));
+    assert_eq!(err.expected.len(), 7);
+
+    let err = "C(b9".parse::<ast::Chord>().unwrap_err();
+    assert_eq!(err.kind, ErrorKind::ExpectedCloseParen);
+    assert_eq!(err.expected, vec![TokKind::Comma, TokKind::RParen]);
+    assert_eq!(err.found, TokKind::EOF);
+
+    let boxed: Box<dyn std::error::Error> = Box::new(err);
+    assert_eq!(boxed.to_string(), "expected ')' after alt");
+}
+
+#[test]
+fn lexer_keeps_non_ascii_characters_whole() {
+    let src = "C — D’ sé";
+    let tokens = lex::tokenize(src);
+    let unknown: Vec<(char, &str)> = tokens
+        .iter()
+        .filter_map(|t| match t.kind {
+            lex::TokKind::Unknown(c) => Some((c, &src[t.span.lo..t.span.hi])),
+            _ => None,
+        })
+        .collect();
+
+    assert_eq!(
+        unknown,
+        vec![('—', "—"), ('’', "’"), ('s', "s"), ('é', "é")]
+    );
+    assert_eq!(tokens.last().unwrap().span.lo, src.len());
+}
+
+#[test]
+fn unicode_symbols_parse_like_ascii() {
+    let parse_one = |src: &str| chords(src)[0].clone();
+
+    for (unicode, ascii) in [
+        ("F♯−7", "F#-7"),
+        ("B♭△7", "Bb^7"),
+        ("C°", "Co"),
+        ("EΔ9", "E^9"),
+    ] {
+        let u = parse_one(unicode);
+        let a = parse_one(ascii);
+        assert_eq!(
+            calc::chord_to_pitch_classes(&u),
+            calc::chord_to_pitch_classes(&a)
+        );
+        assert_eq!(table::chord_to_string(&u, table::SymbolStyle::Ascii), ascii);
+    }
+
+    assert_eq!(
+        table::chord_to_string(&parse_one("F#-7(b9)"), table::SymbolStyle::Unicode),
+        "F♯−7(♭9)"
+    );
+}
+
+#[test]
+fn aliases_map_onto_terse_notation() {
+    let pcs_of = |src: &str| -> Vec<Vec<u8>> {
+        chords(src)
+            .iter()
+            .map(calc::chord_to_pitch_classes)
+            .collect()
+    };
+
+    assert_eq!(
+        pcs_of("Cmaj7 Am7 Bdim Eaug Dmin9 GM7 CM"),
+        pcs_of("C^7 A-7 Bo E+ D-9 G^7 C")
+    );
+    assert_eq!(pcs_of("Cmaj9 AmM7 Bdim7"), pcs_of("C^9 A-^7 Bo7"));
+    assert_eq!(pcs_of("Cmaj6 CM6 AmM6"), pcs_of("C6 C6 A-6"));
+    assert_eq!(chords("Cmaj6")[0], chords("C6")[0]);
+
+    let strict = parse::ParseOptions { strict: true };
+    let (_, errors) = parse::parse_song_with(&lex::tokenize("Cmaj7 | Am7 | G ||"), &strict);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0].kind,
+        parse::ErrorKind::AliasInStrictMode("maj".into())
+    );
+}
+
+#[test]
+fn half_diminished_is_its_own_quality() {
+    for ch in chords("Bø Bø7 B-7b5 Bm7b5 B-7(b5)") {
+        let desc = ch.description.as_ref().unwrap();
+        assert_eq!(desc.qual, Some(ast::Qual::HalfDim));
+        assert!(desc.add.is_empty());
+        assert_eq!(calc::chord_to_pitch_classes(&ch), pc(&[11, 2, 5, 9]));
+        assert_eq!(
+            table::chord_to_string(&ch, table::SymbolStyle::Ascii),
+            "B-7b5"
+        );
+        assert_eq!(
+            table::chord_to_string(&ch, table::SymbolStyle::Unicode),
+            "Bø7"
+        );
+    }
+
+    // extended half-diminished chords fold the same way, and print back as they parse
+    for (src, pcs) in [
+        ("B-9b5", pc(&[11, 2, 5, 9, 1])),
+        ("Bm9b5", pc(&[11, 2, 5, 9, 1])),
+        ("B-11b5", pc(&[11, 2, 5, 9, 1, 4])),
+    ] {
+        let ch = &chords(src)[0];
+        assert_eq!(
+            ch.description.as_ref().unwrap().qual,
+            Some(ast::Qual::HalfDim)
+        );
+        assert_eq!(calc::chord_to_pitch_classes(ch), pcs);
+        let printed = table::chord_to_string(ch, table::SymbolStyle::Ascii);
+        assert_eq!(&chords(&printed)[0], ch);
+    }
+    // a minor sixth or a minor-major seventh with a flat fifth is not half-diminished
+    for src in ["B-6b5", "B-^7b5"] {
+        let ch = &chords(src)[0];
+        assert_eq!(
+            ch.description.as_ref().unwrap().qual,
+            Some(ast::Qual::Minus)
+        );
+    }
+
+    // every half-diminished chord the parser accepts prints back as the same chord...
+    for src in ["Cø", "Cø9", "Cø11", "Cø(#9)", "Cø7sus4"] {
+        let ch = &chords(src)[0];
+        let printed = table::chord_to_string(ch, table::SymbolStyle::Ascii);
+        assert_eq!(&chords(&printed)[0], ch, "{} printed as {}", src, printed);
+    }
+    // ...because ø cannot take another sixth, seventh or fifth
+    for src in ["Cø6", "Cø^7", "Cøb5", "Cø7(b5)"] {
+        let err = parse_chords(src).unwrap_err();
+        assert_eq!(err.kind, parse::ErrorKind::HalfDimConflict, "{}", src);
+        assert_eq!(err.kind.code(), "E022");
+    }
+}
+
+// Every quality against every chord number, all on a C root
+#[test]
+fn seventh_and_extension_intervals_depend_on_quality() {
+    use ast::Qual::*;
+
+    let qnum = |spelling: &str| -> Option<ast::Qnum> {
+        let hat = spelling.starts_with('^');
+        let n: u8 = spelling.trim_start_matches('^').parse().ok()?;
+        let ext = match n {
+            9 => Some(ast::Ext::Nine),
+            11 => Some(ast::Ext::Eleven),
+            13 => Some(ast::Ext::Thirteen),
+            _ => None,
+        };
+        // the parser keeps "^9" as an extension and "9" as a plain number
+        Some(if hat && ext.is_some() {
+            ast::Qnum { hat, n: None, ext }
+        } else {
+            ast::Qnum {
+                hat,
+                n: Some(n),
+                ext: None,
+            }
+        })
+    };
+
+    let numbers = ["", "6", "7", "^7", "9", "^9", "11", "^11", "13", "^13"];
+    let table: [(Option<ast::Qual>, [&[u8]; 10]); 7] = [
+        (
+            None,
+            [
+                &[0, 4, 7],
+                &[0, 4, 7, 9],
+                &[0, 4, 7, 10],
+                &[0, 4, 7, 11],
+                &[0, 2, 4, 7, 10],
+                &[0, 2, 4, 7, 11],
+                &[0, 2, 4, 5, 7, 10],
+                &[0, 2, 4, 5, 7, 11],
+                &[0, 2, 4, 7, 9, 10],
+                &[0, 2, 4, 7, 9, 11],
+            ],
+        ),
+        (
+            Some(Minus),
+            [
+                &[0, 3, 7],
+                &[0, 3, 7, 9],
+                &[0, 3, 7, 10],
+                &[0, 3, 7, 11],
+                &[0, 2, 3, 7, 10],
+                &[0, 2, 3, 7, 11],
+                &[0, 2, 3, 5, 7, 10],
+                &[0, 2, 3, 5, 7, 11],
+                &[0, 2, 3, 7, 9, 10],
+                &[0, 2, 3, 7, 9, 11],
+            ],
+        ),
+        (
+            Some(Plus),
+            [
+                &[0, 4, 8],
+                &[0, 4, 8, 9],
+                &[0, 4, 8, 10],
+                &[0, 4, 8, 11],
+                &[0, 2, 4, 8, 10],
+                &[0, 2, 4, 8, 11],
+                &[0, 2, 4, 5, 8, 10],
+                &[0, 2, 4, 5, 8, 11],
+                &[0, 2, 4, 8, 9, 10],
+                &[0, 2, 4, 8, 9, 11],
+            ],
+        ),
+        (
+            Some(LowerO),
+            [
+                &[0, 3, 6],
+                &[0, 3, 6, 9],
+                &[0, 3, 6, 9],
+                &[0, 3, 6, 11],
+                &[0, 2, 3, 6, 9],
+                &[0, 2, 3, 6, 11],
+                &[0, 2, 3, 5, 6, 9],
+                &[0, 2, 3, 5, 6, 11],
+                &[0, 2, 3, 6, 9],
+                &[0, 2, 3, 6, 9, 11],
+            ],
+        ),
+        (
+            Some(HalfDim),
+            [
+                &[0, 3, 6, 10],
+                &[0, 3, 6, 9, 10],
+                &[0, 3, 6, 10],
+                &[0, 3, 6, 11],
+                &[0, 2, 3, 6, 10],
+                &[0, 2, 3, 6, 11],
+                &[0, 2, 3, 5, 6, 10],
+                &[0, 2, 3, 5, 6, 11],
+                &[0, 2, 3, 6, 9, 10],
+                &[0, 2, 3, 6, 9, 11],
+            ],
+        ),
+        (
+            Some(Five),
+            [
+                &[0, 7],
+                &[0, 7, 9],
+                &[0, 7, 10],
+                &[0, 7, 11],
+                &[0, 2, 7, 10],
+                &[0, 2, 7, 11],
+                &[0, 2, 5, 7, 10],
+                &[0, 2, 5, 7, 11],
+                &[0, 2, 7, 9, 10],
+                &[0, 2, 7, 9, 11],
+            ],
+        ),
+        (
+            Some(One),
+            [
+                &[0],
+                &[0, 9],
+                &[0, 10],
+                &[0, 11],
+                &[0, 2, 10],
+                &[0, 2, 11],
+                &[0, 2, 5, 10],
+                &[0, 2, 5, 11],
+                &[0, 2, 9, 10],
+                &[0, 2, 9, 11],
+            ],
+        ),
+    ];
+
+    for (qual, expected_row) in table {
+        for (number, expected) in numbers.iter().zip(expected_row) {
+            let chord = ast::Chord {
+                root: ast::Note {
+                    letter: ast::Letter::C,
+                    acc: None,
+                    span: Default::default(),
+                },
+                description: Some(ast::Description {
+                    qual,
+                    qnum: qnum(number),
+                    add: vec![],
+                    sus: None,
+                    omit: None,
+                }),
+                bass: None,
+                span: Default::default(),
+            };
+            assert_eq!(
+                calc::chord_to_pitch_classes(&chord),
+                expected,
+                "C with qual {:?} and number {:?}",
+                qual,
+                number
+            );
+        }
+    }
+}
+
+#[test]
+fn several_alterations_per_chord() {
+    let parsed = chords("C7(b9,#11) C7(b9)(#11) C7b9#11");
+    for ch in &parsed {
+        let desc = ch.description.as_ref().unwrap();
+        assert_eq!(desc.add.len(), 2);
+        assert_eq!(calc::chord_to_pitch_classes(ch), pc(&[0, 1, 4, 6, 7, 10]));
+        assert_eq!(
+            table::chord_to_string(ch, table::SymbolStyle::Ascii),
+            "C7(b9,#11)"
+        );
+    }
+
+    // altered fifth and ninth replace the natural ones
+    let g = &chords("G7b9#5")[0];
+    assert_eq!(calc::chord_to_pitch_classes(g), pc(&[7, 11, 3, 5, 8]));
+    let c = &chords("C13(#11)(b9)")[0];
+    assert_eq!(calc::chord_to_pitch_classes(c), pc(&[0, 4, 7, 10, 1, 9, 6]));
+
+    // but two alterations of the same tone are both kept
+    for src in ["C7(b9,#9)", "C7b9#9"] {
+        let ch = &chords(src)[0];
+        assert_eq!(calc::chord_to_pitch_classes(ch), pc(&[0, 1, 3, 4, 7, 10]));
+    }
+    let ch = &chords("C7b5#5")[0];
+    assert_eq!(calc::chord_to_pitch_classes(ch), pc(&[0, 4, 6, 8, 10]));
+    let spelled: Vec<String> = calc::chord_to_spelled_notes(ch)
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+    assert_eq!(spelled, ["C", "E", "Gb", "G#", "Bb"]);
+}
+
+#[test]
+fn sus_placement_rules() {
+    let first = |src: &str| parse_chords(src).map(|chords| chords[0].clone());
+    let pcs = |src: &str| calc::chord_to_pitch_classes(&first(src).unwrap());
+
+    // standard voicings
+    assert_eq!(pcs("C7sus4"), pc(&[0, 5, 7, 10]));
+    assert_eq!(pcs("C9sus4"), pc(&[0, 2, 5, 7, 10]));
+    assert_eq!(pcs("C13sus4"), pc(&[0, 2, 5, 7, 9, 10]));
+    assert_eq!(pcs("C7sus2"), pc(&[0, 2, 7, 10]));
+    assert_eq!(pcs("Csus24"), pc(&[0, 2, 5, 7]));
+
+    // sus before or after the number is the same chord
+    assert_eq!(pcs("Csus4 7"), pcs("C7sus4"));
+    assert_eq!(pcs("Csus4 9"), pcs("C9sus4"));
+
+    // with a quality only the third is replaced
+    assert_eq!(pcs("C-7sus2"), pc(&[0, 2, 7, 10]));
+    assert_eq!(pcs("C+sus4"), pc(&[0, 5, 8]));
+    assert_eq!(pcs("C7(#9)sus4"), pc(&[0, 3, 5, 7, 10]));
+
+    // alterations may also follow a late sus, the usual way to write them
+    assert_eq!(pcs("C7sus4(b9)"), pc(&[0, 1, 5, 7, 10]));
+    assert_eq!(pcs("C7sus4(b9)"), pcs("C7(b9)sus4"));
+    assert_eq!(pcs("C13sus4(#11)"), pcs("C13(#11)sus4"));
+
+    let printed = table::chord_to_string(&first("C13(b9)sus4").unwrap(), table::SymbolStyle::Ascii);
+    assert_eq!(printed, "C13(b9)sus4");
+
+    assert_eq!(
+        first("Csus4 7sus2").unwrap_err().kind,
+        parse::ErrorKind::DuplicateSus
+    );
+    assert_eq!(
+        first("C5sus4").unwrap_err().kind,
+        parse::ErrorKind::SusWithoutThird
+    );
+}
+
+#[test]
+fn chord_tones_are_spelled_from_their_degrees() {
+    let spelled = |src: &str| -> String {
+        calc::chord_to_spelled_notes(&chords(src)[0])
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    assert_eq!(spelled("Db7"), "Db F Ab Cb");
+    assert_eq!(spelled("C#7"), "C# E# G# B");
+    assert_eq!(spelled("Co7"), "C Eb Gb Bbb");
+    assert_eq!(spelled("G#+"), "G# B# Dx");
+    assert_eq!(spelled("F-7b5"), "F Ab Cb Eb");
+    assert_eq!(spelled("Eb7(b9,#11)"), "Eb G Bb Db Fb A");
+    // the #9 sounds like the minor third but is still written, and spelled, as a #9
+    assert_eq!(spelled("C-7(#9)"), "C Eb G Bb D#");
+    assert_eq!(spelled("Bbsus4"), "Bb Eb F");
+    assert_eq!(spelled("C/Bb"), "Bb C E G");
+    assert_eq!(spelled("C^7/E"), "C E G B");
+}
+
+#[test]
+fn double_accidentals_on_root_and_bass() {
+    let chords = chords("Fx F## Bbb-7 C/Ebb G𝄪 A𝄫 ||");
+
+    let printed: Vec<String> = chords
+        .iter()
+        .map(|ch| table::chord_to_string(ch, table::SymbolStyle::Ascii))
+        .collect();
+    assert_eq!(printed, ["Fx", "Fx", "Bbb-7", "C/Ebb", "Gx", "Abb"]);
+
+    // Fx sounds as G, Bbb as A, Ebb as D
+    assert_eq!(calc::chord_to_pitch_classes(&chords[0]), pc(&[7, 11, 2]));
+    assert_eq!(calc::chord_to_pitch_classes(&chords[2]), pc(&[9, 0, 4, 7]));
+    assert_eq!(calc::chord_to_pitch_classes(&chords[3]), vec![2, 0, 4, 7]);
+    assert_eq!(
+        table::chord_to_string(&chords[0], table::SymbolStyle::Unicode),
+        "F𝄪"
+    );
+}
+
+#[test]
+fn transpose_moves_roots_and_bass_into_the_target_key() {
+    use chordcalc::pretty;
+    use chordcalc::transpose::{transpose, SpellingPolicy};
+
+    let song = parse::parse_song(&lex::tokenize("4/4 F#- | C#7 | B-/D E7sus4 ||")).unwrap();
+
+    // F#- up a minor third is A minor, relative to C major: sharps
+    let up = transpose(&song, 3, SpellingPolicy::Auto);
+    assert_eq!(pretty::song_to_string(&up), "4/4 A- | E7 | D-/F G7sus4 ||");
+
+    // down a semitone lands in F minor, relative to Ab major: flats
+    let down = transpose(&song, -1, SpellingPolicy::Auto);
+    assert_eq!(
+        pretty::song_to_string(&down),
+        "4/4 F- | C7 | Bb-/Db Eb7sus4 ||"
+    );
+
+    let forced = transpose(&song, 1, SpellingPolicy::Sharps);
+    assert_eq!(
+        pretty::song_to_string(&forced),
+        "4/4 G- | D7 | C-/D# F7sus4 ||"
+    );
+
+    // a full octave is a no-op
+    let octave = transpose(&song, 12, SpellingPolicy::Auto);
+    assert_eq!(
+        pretty::song_to_string(&octave),
+        pretty::song_to_string(&song)
+    );
+}
+
+#[test]
+fn formatter_output_parses_back_to_the_same_song() {
+    use chordcalc::pretty::{format_song, FormatOptions};
+
+    let layouts = [
+        FormatOptions::default(),
+        FormatOptions {
+            bars_per_line: 0,
+            ..Default::default()
+        },
+        FormatOptions {
+            bars_per_line: 3,
+            align: true,
+            style: table::SymbolStyle::Unicode,
+        },
+    ];
+
+    let mut charts: Vec<String> = ["tests/cases/parser", "tests/cases/calc"]
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).unwrap())
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    charts
+        .push("3/4 Bø7 E7(b9,#13) | A-^9 Bbb/Fx | 5/8 C5 7 Dsus4 9 | Co7no5 C+(#11)sus2 ||".into());
+    charts.push("C:3 G7 | D-7.. G7. | 6/8 NC:2 F^7 A- ||".into());
+    charts.push("C | G | [Verse 1] 3/4 D- | % | *B E7 | [Outro] ||".into());
+    charts.push(
+        "|: Segno C | 1. D :| 2. E || |: F :| |: G To Coda :| A D.S. al Coda | Coda B- ||".into(),
+    );
+
+    for chart in &charts {
+        let song = parse::parse_song(&lex::tokenize(chart)).unwrap();
+        for opts in &layouts {
+            let text = format_song(&song, opts);
+            let reparsed = parse::parse_song(&lex::tokenize(&text))
+                .unwrap_or_else(|e| panic!("{} in formatted chart:\n{}", e, text));
+            assert_eq!(reparsed, song, "formatted chart:\n{}", text);
+        }
+    }
+
+    let song = parse::parse_song(&lex::tokenize("4/4 C | D-7 G7 | C^7 | % | A- | D7 ||")).unwrap();
+    let opts = FormatOptions {
+        bars_per_line: 2,
+        align: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        format_song(&song, &opts),
+        "4/4 C | D-7 G7 |\nC^7   | %      |\nA-    | D7     ||"
+    );
+}
+
+#[test]
+fn chords_notes_and_songs_parse_from_and_print_to_strings() {
+    let chord: ast::Chord = "G7/B".parse().unwrap();
+    assert_eq!(chord.bass.as_ref().unwrap().letter, ast::Letter::B);
+    assert_eq!(chord.to_string(), "G7/B");
+    assert_eq!(
+        "Cmaj7(#11)".parse::<ast::Chord>().unwrap().to_string(),
+        "C^7(#11)"
+    );
+
+    let note: ast::Note = "Bbb".parse().unwrap();
+    assert_eq!(note.acc, Some(ast::Accidental::DoubleFlat));
+    assert_eq!(note.to_string(), "Bbb");
+
+    let song: ast::Song = "4/4 C | A-7 | D-7 G7 ||".parse().unwrap();
+    assert_eq!(song.bars().count(), 3);
+    assert_eq!(song.to_string().parse::<ast::Song>().unwrap(), song);
+
+    assert!("G7 C".parse::<ast::Chord>().is_err());
+    assert!("C7".parse::<ast::Note>().is_err());
+    assert!("H7".parse::<ast::Chord>().is_err());
+}
+
+#[test]
+fn ast_nodes_point_back_into_the_source() {
+    let src = "4/4 C | Ab-7/Gb  D ||";
+    let song = parse::parse_song(&lex::tokenize(src)).unwrap();
+    let text = |span: &lex::Span| &src[span.lo..span.hi];
+
+    assert_eq!(text(&song.sections[0].bars[0].span), "4/4 C");
+    assert_eq!(text(&song.sections[0].bars[1].span), "Ab-7/Gb  D");
+    let ast::BarItem::Chord(ch) = &song.sections[0].bars[1].slots[0].item else {
+        panic!("expected a chord");
+    };
+    assert_eq!(text(&ch.span), "Ab-7/Gb");
+    assert_eq!(text(&ch.root.span), "Ab");
+    assert_eq!(text(&ch.bass.as_ref().unwrap().span), "Gb");
+
+    // spans do not take part in equality
+    assert_eq!(*ch, "Ab-7/Gb".parse::<ast::Chord>().unwrap());
+
+    let chart = "C/C | G5no3 | D/F# ||";
+    let at = |span: &lex::Span| &chart[span.lo..span.hi];
+    let warnings = calc::analyze_song(&parse::parse_song(&lex::tokenize(chart)).unwrap());
+    assert_eq!(warnings.len(), 2);
+    assert_eq!(warnings[0].severity, diag::Severity::Warning);
+    assert_eq!(warnings[0].msg, "bass note duplicates root");
+    assert_eq!(at(&warnings[0].span), "C");
+    assert_eq!(warnings[0].span.lo, 2);
+    assert_eq!(at(&warnings[1].span), "G5no3");
+    assert!(diag::render(chart, &warnings[0]).starts_with("warning: bass note duplicates root"));
+
+    let rows = table::pitch_table_rows(
+        &parse::parse_song(&lex::tokenize(chart)).unwrap(),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(at(&rows[2].span), "D/F#");
+}
+
+#[test]
+fn parser_trace_goes_to_the_attached_sink() {
+    use parse::TraceEvent;
+
+    let tokens = lex::tokenize("C7 | D ||");
+    let mut log = parse::TraceLog::default();
+    let (song, errors) = parse::parse_song_traced(&tokens, &Default::default(), &mut log);
+    assert!(errors.is_empty());
+    assert_eq!(song.bars().count(), 2);
+
+    assert_eq!(log.events.first(), Some(&TraceEvent::Enter("parse_song")));
+    assert_eq!(log.events.last(), Some(&TraceEvent::Exit("parse_song")));
+    let advances: Vec<&TraceEvent> = log
+        .events
+        .iter()
+        .filter(|e| matches!(e, TraceEvent::Advance(_)))
+        .collect();
+    // every token but EOF is consumed exactly once
+    assert_eq!(advances.len(), tokens.len() - 1);
+    assert_eq!(advances[1], &TraceEvent::Advance(lex::TokKind::Num(7)));
+
+    // every rule entered exits again, whichever way it returned, so depth comes back to 0
+    let tokens = lex::tokenize("|: C7 | 1. C7 :| 2. C^9 D.C. al Fine | C7 Fine ||");
+    let mut log = parse::TraceLog::default();
+    let (_, errors) = parse::parse_song_traced(&tokens, &Default::default(), &mut log);
+    assert!(errors.is_empty());
+    let mut open = Vec::new();
+    for event in &log.events {
+        match event {
+            TraceEvent::Enter(rule) => open.push(*rule),
+            TraceEvent::Exit(rule) => assert_eq!(open.pop(), Some(*rule)),
+            TraceEvent::Advance(_) => {}
+        }
+    }
+    assert!(open.is_empty());
+    for rule in ["parse_volta_opt", "parse_end_mark", "parse_qnum_opt"] {
+        assert!(log.events.contains(&TraceEvent::Exit(rule)));
+    }
+}
+
+#[test]
+fn slots_get_start_beats_and_durations_from_the_meter() {
+    let timing = |src: &str| -> Vec<Vec<(f32, f32)>> {
+        let song = parse::parse_song(&lex::tokenize(src)).unwrap();
+        song.bars()
+            .map(|bar| bar.slots.iter().map(|s| (s.start, s.duration)).collect())
+            .collect()
+    };
+
+    // 4/4 until a bar says otherwise; unmarked chords split the bar evenly
+    assert_eq!(
+        timing("B- C#7 | C | 3/4 D E F | G ||"),
+        vec![
+            vec![(0.0, 2.0), (2.0, 2.0)],
+            vec![(0.0, 4.0)],
+            vec![(0.0, 1.0), (1.0, 1.0), (2.0, 1.0)],
+            vec![(0.0, 3.0)],
+        ]
+    );
+
+    // written lengths win and the rest share what is left
+    assert_eq!(
+        timing("C:3 D | 5/4 C.. D E ||"),
+        timing("C.. D | 5/4 C:3 D E ||")
+    );
+    assert_eq!(
+        timing("C:3 D | 5/4 C.. D E ||"),
+        vec![
+            vec![(0.0, 3.0), (3.0, 1.0)],
+            vec![(0.0, 3.0), (3.0, 1.0), (4.0, 1.0)],
+        ]
+    );
+
+    let err = parse::parse_song(&lex::tokenize("C:0 D ||")).unwrap_err();
+    assert_eq!(err.kind, parse::ErrorKind::InvalidDuration(0));
+}
+
+#[test]
+fn meters_carry_over_and_bars_are_checked_against_them() {
+    use chordcalc::meter;
+
+    let song = parse::parse_song(&lex::tokenize("C | 3/4 D | E | 6/8 F | G ||")).unwrap();
+    let meters: Vec<(u16, u16)> = meter::effective_meters(&song)
+        .iter()
+        .map(|m| (m.numerator, m.denominator))
+        .collect();
+    assert_eq!(meters, vec![(4, 4), (3, 4), (3, 4), (6, 8), (6, 8)]);
+
+    let warnings = |src: &str| -> Vec<String> {
+        let song = parse::parse_song(&lex::tokenize(src)).unwrap();
+        meter::check_bars(&song)
+            .into_iter()
+            .map(|d| d.msg)
+            .collect()
+    };
+    assert!(warnings("4/4 C D | E F G A | 3/4 C D E | 6/8 C D E | % ||").is_empty());
+    assert_eq!(
+        warnings("C D E | C:3 D:2 | C:2 D:1 | 3/4 % | 4/4 C % ||"),
+        vec![
+            "3 chords do not share the 4 beat(s) left in 4/4 evenly",
+            "bar has 5 beats written but 4/4 has only 4",
+            "bar is 1 beat(s) short of 4/4",
+            "meter changes to 3/4 on a `%` bar, but the bar it repeats is in 4/4",
+            "`%` repeats a whole bar and should stand alone",
+        ]
+    );
+    // a bar that only holds navigation marks has no length of its own
+    assert!(warnings("Segno C | D | D.S. al Fine | E Fine ||").is_empty());
+}
+
+#[test]
+fn repeat_bars_expand_into_the_bar_they_repeat() {
+    use chordcalc::expand::expand_repeats;
+
+    let song = parse::parse_song(&lex::tokenize("C D- | % | % | 3/4 % | G ||")).unwrap();
+    let expanded = expand_repeats(&song).unwrap();
+    assert_eq!(
+        expanded,
+        parse::parse_song(&lex::tokenize("C D- | C D- | C D- | 3/4 C D- | G ||")).unwrap()
+    );
+    // the repeat in 3/4 is played in 3/4
+    assert_eq!(expanded.sections[0].bars[3].slots[1].duration, 1.5);
+
+    let err = expand_repeats(&parse::parse_song(&lex::tokenize("% | C ||")).unwrap()).unwrap_err();
+    assert_eq!(err.severity, diag::Severity::Error);
+    assert_eq!(err.span.lo, 0);
+
+    let rows = |count_repeats| {
+        let opts = table::TableOptions {
+            count_repeats,
+            ..Default::default()
+        };
+        table::pitch_table_rows(&song, &opts).unwrap().len()
+    };
+    assert_eq!(rows(false), 3);
+    assert_eq!(rows(true), 9);
+    // the table cannot count a % that repeats nothing, and says why
+    let lead = parse::parse_song(&lex::tokenize("% | C ||")).unwrap();
+    let opts = table::TableOptions {
+        count_repeats: true,
+        ..Default::default()
+    };
+    let table_err = table::pitch_table_rows(&lead, &opts).unwrap_err();
+    assert_eq!(table_err.msg, err.msg);
+    assert_eq!(table_err.span.lo, 0);
+}
+
+#[test]
+fn section_labels_group_bars() {
+    let song = parse::parse_song(&lex::tokenize("C | [Verse 1] D | E | *B F | G ||")).unwrap();
+    let form: Vec<(Option<&str>, usize)> = song
+        .sections
+        .iter()
+        .map(|s| (s.label.as_deref(), s.bars.len()))
+        .collect();
+    assert_eq!(form, vec![(None, 1), (Some("Verse 1"), 2), (Some("B"), 2)]);
+    assert_eq!(song.bars().count(), 5);
+
+    // sections start on their own line, under their label
+    assert_eq!(
+        chordcalc::pretty::song_to_string(&song),
+        "C |\n[Verse 1]\nD | E |\n[B]\nF | G ||"
+    );
+
+    let rows = table::pitch_table_rows(
+        &song,
+        &table::TableOptions {
+            by_section: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let sections: Vec<usize> = rows.iter().map(|r| r.section).collect();
+    assert_eq!(sections, vec![0, 1, 1, 2, 2]);
+
+    let err = parse::parse_song(&lex::tokenize("C [Verse] D ||")).unwrap_err();
+    assert_eq!(err.kind, parse::ErrorKind::LabelInsideBar);
+}
+
+#[test]
+fn repeats_endings_and_jumps_unroll_into_playing_order() {
+    use chordcalc::expand::unroll;
+
+    let played = |src: &str| -> String {
+        let song = parse::parse_song(&lex::tokenize(src)).unwrap();
+        let played = unroll(&song).unwrap_or_else(|d| panic!("{}", d.msg));
+        played
+            .bars()
+            .map(|bar| {
+                chordcalc::pretty::song_to_string(&ast::Song {
+                    metadata: Default::default(),
+                    sections: vec![ast::Section {
+                        label: None,
+                        comments: Vec::new(),
+                        bars: vec![bar.clone()],
+                    }],
+                })
+            })
+            .map(|s| s.trim_end_matches(" ||").to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    assert_eq!(played("|: C | 1. D :| 2. E ||"), "C D C E");
+    // an ending shared by several passes
+    assert_eq!(played("|: C | 1. 2. D :| 3. F ||"), "C D C D C F");
+    let song = parse::parse_song(&lex::tokenize("|: C | 1. 2. D :| 3. F ||")).unwrap();
+    assert_eq!(song.bars().nth(1).unwrap().volta, vec![1, 2]);
+    assert!(chordcalc::pretty::song_to_string(&song).contains("1. 2. D :|"));
+    assert_eq!(played("C :| D ||"), "C C D");
+    assert_eq!(played("|: C | D :||: E | F :|| G ||"), "C D C D E F E F G");
+    assert_eq!(played("C | D Fine | E D.C. al Fine ||"), "C D E C D");
+    assert_eq!(
+        played("C | Segno D | E To Coda | F D.S. al Coda | Coda G ||"),
+        "C D E F D E G"
+    );
+    // after the jump, repeats are not taken again and only the last ending is played
+    assert_eq!(played("|: C | 1. D :| 2. E | F D.C. ||"), "C D C E F C E F");
+    // the played form states a meter wherever it changes
+    assert_eq!(played("C | 3/4 D D.C. ||"), "C 3/4 D 4/4 C 3/4 D");
+    // a bar of marks alone is not played, and the played form parses back
+    assert_eq!(
+        played("Segno C | D | D.S. al Fine | E Fine ||"),
+        "C D C D E"
+    );
+    let song = parse::parse_song(&lex::tokenize(
+        "C | D |\n# back to the top\nD.C. al Fine |\nE Fine ||",
+    ))
+    .unwrap();
+    let performed = unroll(&song).unwrap();
+    let text = chordcalc::pretty::song_to_string(&performed);
+    assert_eq!(text, "C | D |\n# back to the top\nC | D |\nE ||");
+    assert_eq!(parse::parse_song(&lex::tokenize(&text)).unwrap(), performed);
+
+    let song = parse::parse_song(&lex::tokenize("C | D D.S. ||")).unwrap();
+    assert_eq!(
+        unroll(&song).unwrap_err().msg,
+        "D.S., but the chart has no Segno"
+    );
+
+    let song = parse::parse_song(&lex::tokenize("|: C | 3/4 D :| Fine ||")).unwrap();
+    let bar = song.bars().nth(1).unwrap();
+    assert!(bar.end == ast::BarLine::RepeatEnd && !bar.repeat_start);
+    assert!(chordcalc::meter::check_bars(&song)
+        .iter()
+        .any(|d| d.msg == "meter changes to 3/4 inside a repeat that starts in 4/4"));
+}
+
+#[test]
+fn header_block_fills_song_metadata() {
+    let src = "title: Don't Stop Believin'\nartist: Journey\nkey: F#m\ntempo: 119\nmeter: 3/4\n\nF#- D | A E ||";
+    let song = parse::parse_song(&lex::tokenize(src)).unwrap();
+    let meta = &song.metadata;
+    assert_eq!(meta.title.as_deref(), Some("Don't Stop Believin'"));
+    assert_eq!(meta.artist.as_deref(), Some("Journey"));
+    assert_eq!(meta.key.as_ref().unwrap().to_string(), "F#m");
+    let tonic = &meta.key.as_ref().unwrap().tonic.span;
+    assert_eq!(&src[tonic.lo..tonic.hi], "F#");
+    assert_eq!(meta.tempo, Some(119));
+    assert_eq!(meta.style, None);
+
+    // the header's meter applies until a bar states another
+    let bar = &song.sections[0].bars[0];
+    assert_eq!(bar.meter, None);
+    assert_eq!(bar.slots[1].start, 1.5);
+
+    // the header survives formatting, and the key moves with the chords
+    let text = chordcalc::pretty::song_to_string(&song);
+    assert!(text.starts_with("title: Don't Stop Believin'\nartist: Journey\nkey: F#m\n"));
+    assert_eq!(parse::parse_song(&lex::tokenize(&text)).unwrap(), song);
+    let moved = chordcalc::transpose::transpose(&song, 3, Default::default());
+    assert_eq!(moved.metadata.key.unwrap().to_string(), "Am");
+
+    // keys take the same major and minor suffixes as chords, or the words for them
+    let key = |value: &str| {
+        let src = format!("key: {}\nC ||", value);
+        let song = parse::parse_song(&lex::tokenize(&src)).unwrap();
+        song.metadata.key.unwrap().to_string()
+    };
+    assert_eq!(key("Cmaj"), "C");
+    assert_eq!(key("C major"), "C");
+    assert_eq!(key("A minor"), "Am");
+    assert_eq!(key("Ebmin"), "Ebm");
+
+    let kinds = |src: &str| -> Vec<parse::ErrorKind> {
+        let (_, errors) = parse::parse_song_recovering(&lex::tokenize(src));
+        errors.into_iter().map(|e| e.kind).collect()
+    };
+    assert_eq!(
+        kinds("key: H\ntempo: 0\ncapo: 2\ntempo: 90\ntempo: 100\nC ||"),
+        vec![
+            parse::ErrorKind::InvalidHeaderValue("key".into(), "H".into()),
+            parse::ErrorKind::InvalidHeaderValue("tempo".into(), "0".into()),
+            parse::ErrorKind::UnknownHeaderField("capo".into()),
+            parse::ErrorKind::DuplicateHeaderField("tempo".into()),
+        ]
+    );
+}
+
+#[test]
+fn comments_are_kept_with_the_bars_they_annotate() {
+    let src = "# from the live take\ntitle: Test\n\n[Intro]\nC#7 | G7 // check this chord\n| C /* hold */ | F ||\n[Solo]\n# guitar solo here\nA- ||";
+    let tokens = lex::tokenize(src);
+    let comments: Vec<&str> = tokens
+        .iter()
+        .filter(|t| matches!(t.kind, lex::TokKind::Comment { .. }))
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(
+        comments,
+        vec![
+            "# from the live take",
+            "// check this chord",
+            "/* hold */",
+            "# guitar solo here"
+        ]
+    );
+
+    // comments are trivia: the chords parse as if they were not there
+    let song = parse::parse_song(&tokens).unwrap();
+    assert_eq!(song.bars().count(), 5);
+    assert_eq!(song.metadata.comments, vec!["# from the live take"]);
+    assert!(song.sections[0].comments.is_empty());
+    assert_eq!(
+        song.sections[0].bars[1].comments_after,
+        vec!["// check this chord"]
+    );
+    assert_eq!(
+        song.sections[1].bars[0].comments_before,
+        vec!["# guitar solo here"]
+    );
+
+    let text = chordcalc::pretty::song_to_string(&song);
+    assert_eq!(
+        text,
+        "# from the live take\ntitle: Test\n[Intro]\nC#7 | G7 | // check this chord\nC | /* hold */ F ||\n[Solo]\n# guitar solo here\nA- ||"
+    );
+    assert_eq!(parse::parse_song(&lex::tokenize(&text)).unwrap(), song);
+    // a sharp after a space is still a sharp when a number or note follows it
+    for chart in ["C7(b9, #11) | F ||", "C7 #9 | F ||"] {
+        let tokens = lex::tokenize(chart);
+        assert!(!tokens
+            .iter()
+            .any(|t| matches!(t.kind, lex::TokKind::Comment { .. })));
+        let song = parse::parse_song(&tokens).unwrap();
+        assert_eq!(song.bars().count(), 2);
+    }
+    // but a comment may start with a number or a note, on its own line or after a bar
+    for (chart, printed) in [
+        (
+            "C | D ||\n#2nd ending is the outro",
+            "C | D ||\n#2nd ending is the outro",
+        ),
+        ("C | D ||\n#Bridge: vamp", "C | D || #Bridge: vamp"),
+        ("C | #Fix this chord\nD ||", "C | #Fix this chord\nD ||"),
+    ] {
+        let song = parse::parse_song(&lex::tokenize(chart)).unwrap();
+        assert_eq!(song.bars().count(), 2);
+        let text = chordcalc::pretty::song_to_string(&song);
+        assert_eq!(text, printed);
+        assert_eq!(parse::parse_song(&lex::tokenize(&text)).unwrap(), song);
+    }
+}
+
+#[test]
+fn chordpro_lyrics_read_into_the_same_song() {
+    use chordcalc::chordpro::parse_chordpro;
+
+    let src = "{title: The Sound of Silence}\n{key: Dm}\n{start_of_verse}\n[Dm]Hello darkness, my old [C]friend\nI've come to talk with you a[Dm]gain\n{end_of_verse}\n{c: Solo}\n[F]La la [C/E]la\n";
+    let (song, diags) = parse_chordpro(src, &parse::ParseOptions::default());
+    assert!(diags.is_empty());
+    assert_eq!(song.metadata.title.as_deref(), Some("The Sound of Silence"));
+    assert_eq!(song.metadata.key.as_ref().unwrap().to_string(), "Dm");
+    assert_eq!(song.sections[0].label.as_deref(), Some("Verse"));
+    assert_eq!(song.sections[1].label, None);
+    assert_eq!(song.sections[1].bars[0].comments_before, vec!["# Solo"]);
+
+    // each chord is a bar, carrying the words sung over it
+    let lyrics: Vec<&str> = song
+        .bars()
+        .map(|bar| bar.slots[0].lyric.as_deref().unwrap_or(""))
+        .collect();
+    assert_eq!(
+        lyrics,
+        vec![
+            "Hello darkness, my old ",
+            "friend\nI've come to talk with you a",
+            "gain\n",
+            "La la ",
+            "la"
+        ]
+    );
+    let chord = match &song.bars().nth(4).unwrap().slots[0].item {
+        ast::BarItem::Chord(ch) => ch,
+        other => panic!("expected a chord, got {:?}", other),
+    };
+    assert_eq!(&src[chord.span.lo..chord.span.hi], "C/E");
+
+    // the table sees the same chords as in the bar chart
+    let chart = parse::parse_song(&lex::tokenize("D- | C | D- | F | C/E ||")).unwrap();
+    let names = |song: &ast::Song| -> Vec<String> {
+        table::pitch_table_rows(song, &Default::default())
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect()
+    };
+    assert_eq!(names(&song), names(&chart));
+
+    // written back as ChordPro, transposed lyric sheets keep their words
+    let moved = chordcalc::transpose::transpose(&song, 2, Default::default());
+    let text = chordcalc::chordpro::song_to_chordpro(&moved, Default::default());
+    assert_eq!(
+        text,
+        "{title: The Sound of Silence}\n{key: Em}\n{start_of_verse}\n[E-]Hello darkness, my old [D]friend\nI've come to talk with you a[E-]gain\n{end_of_verse}\n# Solo\n[G]La la [D/F#]la"
+    );
+    let (back, _) = parse_chordpro(&text, &Default::default());
+    assert_eq!(back, moved);
+
+    let (song, diags) = parse_chordpro("{capo: 2}\n[Am]Hello [H7]there\n", &Default::default());
+    // the words after a chord that does not parse are still sung
+    assert_eq!(
+        song.bars().next().unwrap().slots[0].lyric.as_deref(),
+        Some("Hello there")
+    );
+    let msgs: Vec<(diag::Severity, &str)> =
+        diags.iter().map(|d| (d.severity, d.msg.as_str())).collect();
+    assert_eq!(
+        msgs,
+        vec![
+            (
+                diag::Severity::Warning,
+                "directive `capo` is not supported and is ignored"
+            ),
+            (diag::Severity::Error, "unknown character 'H'"),
+        ]
+    );
+    assert_eq!(diags[1].span.lo, "{capo: 2}\n[Am]Hello [".len());
+}